@@ -0,0 +1,981 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::expr::{self, Expr};
+use crate::filters;
+use crate::placeholder::{self, Occurrence, OPEN};
+use crate::values::{is_placeholder_name, list_key, list_len};
+
+/// Values that make a condition false, compared case-insensitively.
+/// Unset and empty values are false too; anything else is true.
+pub const FALSE_VALUES: &[&str] = &["false", "0", "no", "off"];
+
+/// Prefix of the variables describing the innermost loop
+const LOOP_PREFIX: &str = "loop.";
+
+/// Variables available as `loop.<name>` inside `@[for]@`, with a short description of each
+pub const LOOP_VARIABLES: &[(&str, &str)] = &[
+    ("index", "position of the item, starting at 0"),
+    ("first", "true for the first item, false otherwise"),
+    ("last", "true for the last item, false otherwise"),
+];
+
+/// A block tag written between the placeholder delimiters, such as `@[if serde]@`
+#[derive(Debug, Clone)]
+pub enum Tag {
+    If(Condition),
+    Elif(Condition),
+    Else,
+    EndIf,
+    For { var: String, list: String },
+    EndFor,
+    /// `include "path"`; the path is as written, not yet resolved
+    Include(String),
+    /// `extends "path"`, naming the base template
+    Extends(String),
+    Block(String),
+    EndBlock,
+    /// `super`: the content of the overridden block in the base template
+    Super,
+    /// `raw`: text up to `@[endraw]@` is copied to the output as it is
+    Raw,
+    EndRaw,
+    /// `# text`: a note for template authors, removed when rendering
+    Comment(String),
+}
+
+impl Tag {
+    /// Recognize a tag from the trimmed text between the delimiters.
+    /// Returns `None` for ordinary placeholders.
+    pub fn parse(key: &str) -> Option<Result<Self, String>> {
+        if let Some(text) = key.strip_prefix('#') {
+            return Some(Ok(Tag::Comment(text.trim().into())));
+        }
+        let (keyword, rest) = match key.split_once(char::is_whitespace) {
+            Some((keyword, rest)) => (keyword, rest.trim()),
+            None => (key, ""),
+        };
+        let tag = match keyword {
+            "if" => Condition::parse(rest).map(Tag::If),
+            "elif" => Condition::parse(rest).map(Tag::Elif),
+            "else" => Self::bare(Tag::Else, keyword, rest),
+            "endif" => Self::bare(Tag::EndIf, keyword, rest),
+            "for" => Self::parse_for(rest),
+            "endfor" => Self::bare(Tag::EndFor, keyword, rest),
+            "include" => Self::quoted_path(keyword, rest).map(Tag::Include),
+            "extends" => Self::quoted_path(keyword, rest).map(Tag::Extends),
+            "block" if is_placeholder_name(rest) => Ok(Tag::Block(rest.into())),
+            "block" => Err("expected 'block <name>'".into()),
+            "endblock" => Self::bare(Tag::EndBlock, keyword, rest),
+            "super" => Self::bare(Tag::Super, keyword, rest),
+            "raw" => Self::bare(Tag::Raw, keyword, rest),
+            "endraw" => Self::bare(Tag::EndRaw, keyword, rest),
+            _ => return None,
+        };
+        Some(tag)
+    }
+
+    /// Whether a placeholder key is a block tag rather than a placeholder
+    pub fn is_tag(key: &str) -> bool {
+        Self::parse(key).is_some()
+    }
+
+    fn bare(tag: Tag, keyword: &str, rest: &str) -> Result<Self, String> {
+        if rest.is_empty() {
+            Ok(tag)
+        } else {
+            Err(format!("unexpected '{}' after '{}'", rest, keyword))
+        }
+    }
+
+    /// `for <var> in <list>`
+    fn parse_for(rest: &str) -> Result<Self, String> {
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        let [var, "in", list] = words[..] else {
+            return Err("expected 'for <name> in <list>'".into());
+        };
+        for name in [var, list] {
+            if !is_placeholder_name(name) {
+                return Err(format!("'{}' is not a valid placeholder name", name));
+            }
+        }
+        if var == "loop" {
+            return Err("'loop' is reserved for the loop variables".into());
+        }
+        Ok(Tag::For { var: var.into(), list: list.into() })
+    }
+
+    /// The `"path"` of `include` and `extends`
+    fn quoted_path(keyword: &str, rest: &str) -> Result<String, String> {
+        match rest.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) {
+            Some(path) if !path.is_empty() => Ok(path.into()),
+            _ => Err(format!("expected '{} \"<path>\"' with a quoted path", keyword)),
+        }
+    }
+
+    /// Word naming the tag in messages
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            Tag::If(_) => "if",
+            Tag::Elif(_) => "elif",
+            Tag::Else => "else",
+            Tag::EndIf => "endif",
+            Tag::For { .. } => "for",
+            Tag::EndFor => "endfor",
+            Tag::Include(_) => "include",
+            Tag::Extends(_) => "extends",
+            Tag::Block(_) => "block",
+            Tag::EndBlock => "endblock",
+            Tag::Super => "super",
+            Tag::Raw => "raw",
+            Tag::EndRaw => "endraw",
+            Tag::Comment(_) => "#",
+        }
+    }
+}
+
+/// Whether `name` is one of the `loop.<name>` variables
+fn is_loop_variable(name: &str) -> bool {
+    name.strip_prefix(LOOP_PREFIX)
+        .is_some_and(|field| LOOP_VARIABLES.iter().any(|(known, _)| *known == field))
+}
+
+/// Test applied by `@[if]@` and `@[elif]@` to the value map
+#[derive(Debug, Clone)]
+pub enum Condition {
+    /// `name`: the value is truthy
+    Truthy(String),
+    /// `not name`
+    Not(Box<Condition>),
+    /// `name == "text"`, or `name != "text"` when negated
+    Equals { name: String, text: String, negated: bool },
+}
+
+impl Condition {
+    fn parse(text: &str) -> Result<Self, String> {
+        if let Some(rest) = text.strip_prefix("not ") {
+            return Ok(Condition::Not(Box::new(Self::parse(rest.trim())?)));
+        }
+
+        let comparison = text.split_once("==").map(|(l, r)| (l, r, false))
+            .or_else(|| text.split_once("!=").map(|(l, r)| (l, r, true)));
+        let (name, condition) = match comparison {
+            Some((name, text, negated)) => {
+                let text = text.trim();
+                let text = text.strip_prefix('"')
+                    .and_then(|t| t.strip_suffix('"'))
+                    .unwrap_or(text);
+                let name = name.trim();
+                (name, Condition::Equals { name: name.into(), text: text.into(), negated })
+            }
+            None => (text, Condition::Truthy(text.into())),
+        };
+
+        if name.is_empty() {
+            Err("expected a placeholder name in condition".into())
+        } else if !is_placeholder_name(name) && !is_loop_variable(name) {
+            Err(format!("'{}' is not a valid placeholder name", name))
+        } else {
+            Ok(condition)
+        }
+    }
+
+    /// Name of the value this condition tests
+    pub fn name(&self) -> &str {
+        match self {
+            Condition::Truthy(name) | Condition::Equals { name, .. } => name,
+            Condition::Not(inner) => inner.name(),
+        }
+    }
+
+    fn eval(&self, scope: &Scope) -> bool {
+        match self {
+            Condition::Truthy(name) => scope.is_truthy(name),
+            Condition::Not(inner) => !inner.eval(scope),
+            Condition::Equals { name, text, negated } => {
+                (scope.value(name).unwrap_or_default() == *text) != *negated
+            }
+        }
+    }
+}
+
+/// Whether a value counts as true in a condition
+pub fn is_truthy(value: Option<&str>) -> bool {
+    match value {
+        None => false,
+        Some(value) => {
+            let value = value.trim();
+            !value.is_empty() && !FALSE_VALUES.iter().any(|f| value.eq_ignore_ascii_case(f))
+        }
+    }
+}
+
+/// Template body parsed into text and blocks
+#[derive(Debug)]
+pub enum Node {
+    /// Body text, placeholders included, copied to the output as it is
+    Text(Range<usize>),
+    /// `@[if]@` with its `@[elif]@` branches and the `@[else]@` nodes
+    If { branches: Vec<(Condition, Vec<Node>)>, otherwise: Vec<Node> },
+    /// `@[for var in list]@`, repeated for each item of the list
+    For { var: String, list: String, offset: usize, body: Vec<Node> },
+    /// `@[include "path"]@` not yet replaced by the partial
+    Include { offset: usize },
+    /// `@[block name]@`, rendered in place; overriding happens before parsing
+    Block(Vec<Node>),
+    /// Content of `@[raw]@`, copied to the output without substitution
+    Raw(Range<usize>),
+}
+
+/// Problem in the block structure of a body, at a byte offset
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub offset: usize,
+    pub message: String,
+}
+
+/// A block whose closing tag has not been reached yet
+struct OpenBlock {
+    offset: usize,
+    kind: OpenKind,
+    /// Nodes of the enclosing list, restored when the block is closed
+    parent: Vec<Node>,
+}
+
+enum OpenKind {
+    If {
+        branches: Vec<(Condition, Vec<Node>)>,
+        /// Condition of the branch being read, or `None` once past `@[else]@`
+        current: Option<Condition>,
+    },
+    For { var: String, list: String },
+    Block,
+    Raw,
+}
+
+impl OpenKind {
+    fn keyword(&self) -> &'static str {
+        match self {
+            OpenKind::If { .. } => "if",
+            OpenKind::For { .. } => "for",
+            OpenKind::Block => "block",
+            OpenKind::Raw => "raw",
+        }
+    }
+}
+
+/// Byte range of a tag token, widened to its whole line when the tag stands
+/// alone on it so block tags do not leave blank lines behind
+fn tag_span(body: &str, span: Range<usize>) -> Range<usize> {
+    let line_start = body[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = body[span.end..].find('\n').map_or(body.len(), |i| span.end + i + 1);
+    let before = &body[line_start..span.start];
+    let after = &body[span.end..line_end];
+    if before.trim().is_empty() && after.trim().is_empty() {
+        line_start..line_end
+    } else {
+        span
+    }
+}
+
+/// Parse the block structure of a body
+pub fn parse(body: &str) -> Result<Vec<Node>, SyntaxError> {
+    let mut nodes = Vec::new();
+    let mut open: Vec<OpenBlock> = Vec::new();
+    let mut cursor = 0;
+
+    for occurrence in placeholder::tokens(body) {
+        let offset = occurrence.span.start;
+        let in_loop = open.iter().any(|block| matches!(block.kind, OpenKind::For { .. }));
+        let Some(tag) = Tag::parse(&occurrence.key) else {
+            if expr::is_expression(&occurrence.key) {
+                let start = occurrence.key_start(body);
+                let expression = Expr::parse(&occurrence.key)
+                    .map_err(|e| SyntaxError { offset: start + e.offset, message: e.message })?;
+                for (name, _) in expression.names() {
+                    check_loop_variable(&name, in_loop, offset)?;
+                }
+            } else {
+                check_loop_variable(&occurrence.name, in_loop, offset)?;
+            }
+            continue;
+        };
+        let tag = tag.map_err(|message| SyntaxError { offset, message })?;
+        if let Tag::If(condition) | Tag::Elif(condition) = &tag {
+            check_loop_variable(condition.name(), in_loop, offset)?;
+        }
+
+        let span = tag_span(body, occurrence.span);
+        if cursor < span.start {
+            nodes.push(Node::Text(cursor..span.start));
+        }
+        cursor = span.end;
+
+        let error = |message: String| SyntaxError { offset, message };
+        let closes = |expected: &str, block: Option<&OpenBlock>| match block {
+            Some(block) if block.kind.keyword() == expected => Ok(()),
+            Some(block) => Err(error(format!(
+                "'@[{}]@' inside '@[{}]@', which is still open",
+                tag.keyword(), block.kind.keyword()
+            ))),
+            None => Err(error(format!(
+                "'@[{}]@' without a matching '@[{}]@'",
+                tag.keyword(), expected
+            ))),
+        };
+
+        match tag {
+            Tag::Comment(_) => {}
+            Tag::Include(_) => nodes.push(Node::Include { offset }),
+            Tag::Extends(_) => {
+                return Err(error("'@[extends]@' must come first in the template".into()));
+            }
+            Tag::Super => {
+                return Err(error(
+                    "'@[super]@' can only be used in a block that overrides one of the base template".into()
+                ));
+            }
+            Tag::Block(_) => open.push(OpenBlock {
+                offset,
+                kind: OpenKind::Block,
+                parent: std::mem::take(&mut nodes),
+            }),
+            Tag::EndBlock => {
+                closes("block", open.last())?;
+                let block = open.pop().unwrap();
+                let body = std::mem::replace(&mut nodes, block.parent);
+                nodes.push(Node::Block(body));
+            }
+            Tag::Raw => open.push(OpenBlock {
+                offset,
+                kind: OpenKind::Raw,
+                parent: std::mem::take(&mut nodes),
+            }),
+            Tag::EndRaw => {
+                closes("raw", open.last())?;
+                let block = open.pop().unwrap();
+                // Tokens inside are skipped, so the content is a single run of text
+                let content = std::mem::replace(&mut nodes, block.parent);
+                nodes.extend(content.into_iter().filter_map(|node| match node {
+                    Node::Text(range) => Some(Node::Raw(range)),
+                    _ => None,
+                }));
+            }
+            Tag::If(condition) => open.push(OpenBlock {
+                offset,
+                kind: OpenKind::If { branches: Vec::new(), current: Some(condition) },
+                parent: std::mem::take(&mut nodes),
+            }),
+            Tag::For { var, list } => open.push(OpenBlock {
+                offset,
+                kind: OpenKind::For { var, list },
+                parent: std::mem::take(&mut nodes),
+            }),
+            Tag::Elif(_) | Tag::Else => {
+                closes("if", open.last())?;
+                let Some(OpenBlock { kind: OpenKind::If { branches, current }, .. }) = open.last_mut() else {
+                    unreachable!();
+                };
+                let Some(previous) = current.take() else {
+                    return Err(error(format!("'@[{}]@' after '@[else]@'", tag.keyword())));
+                };
+                branches.push((previous, std::mem::take(&mut nodes)));
+                if let Tag::Elif(condition) = tag {
+                    *current = Some(condition);
+                }
+            }
+            Tag::EndIf => {
+                closes("if", open.last())?;
+                let block = open.pop().unwrap();
+                let OpenKind::If { mut branches, current } = block.kind else {
+                    unreachable!();
+                };
+                let otherwise = match current {
+                    Some(condition) => {
+                        branches.push((condition, std::mem::take(&mut nodes)));
+                        Vec::new()
+                    }
+                    None => std::mem::take(&mut nodes),
+                };
+                nodes = block.parent;
+                nodes.push(Node::If { branches, otherwise });
+            }
+            Tag::EndFor => {
+                closes("for", open.last())?;
+                let block = open.pop().unwrap();
+                let OpenKind::For { var, list } = block.kind else {
+                    unreachable!();
+                };
+                let body = std::mem::replace(&mut nodes, block.parent);
+                nodes.push(Node::For { var, list, offset: block.offset, body });
+            }
+        }
+    }
+
+    if let Some(block) = open.last() {
+        let keyword = block.kind.keyword();
+        return Err(SyntaxError {
+            offset: block.offset,
+            message: format!("'@[{}]@' is never closed with '@[end{}]@'", keyword, keyword),
+        });
+    }
+    if cursor < body.len() {
+        nodes.push(Node::Text(cursor..body.len()));
+    }
+    Ok(nodes)
+}
+
+/// An `@[include]@` or `@[extends]@` tag, naming another template file
+#[derive(Debug)]
+pub struct FileTag {
+    /// Byte range the tag takes up: the tag, or its whole line when it stands alone
+    pub span: Range<usize>,
+    /// Byte offset of the tag itself
+    pub offset: usize,
+    pub path: String,
+}
+
+/// Find the well-formed `@[include]@` tags of a body, in order.
+/// Malformed ones are left for `parse` to report.
+pub fn includes(body: &str) -> Vec<FileTag> {
+    placeholder::tokens(body)
+        .into_iter()
+        .filter_map(|occurrence| match Tag::parse(&occurrence.key) {
+            Some(Ok(Tag::Include(path))) => Some(FileTag {
+                offset: occurrence.span.start,
+                span: tag_span(body, occurrence.span),
+                path,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The `@[extends]@` tag of a body, which must come before anything but whitespace
+pub fn extends(body: &str) -> Result<Option<FileTag>, SyntaxError> {
+    let tokens = placeholder::tokens(body);
+    let mut tags = tokens.into_iter().filter_map(|occurrence| match Tag::parse(&occurrence.key) {
+        Some(Ok(Tag::Extends(path))) => Some(FileTag {
+            offset: occurrence.span.start,
+            span: tag_span(body, occurrence.span),
+            path,
+        }),
+        _ => None,
+    });
+    let Some(tag) = tags.next() else {
+        return Ok(None);
+    };
+    if first_text(body, 0..tag.offset).is_some() {
+        return Err(SyntaxError {
+            offset: tag.offset,
+            message: "'@[extends]@' must come first in the template".into(),
+        });
+    }
+    if let Some(second) = tags.next() {
+        return Err(SyntaxError {
+            offset: second.offset,
+            message: "a template can only extend one base template".into(),
+        });
+    }
+    Ok(Some(tag))
+}
+
+/// The `@[# ...]@` comments of a body, as the byte range of each tag and its text
+pub fn comments(body: &str) -> Vec<(Range<usize>, String)> {
+    placeholder::tokens(body)
+        .into_iter()
+        .filter_map(|occurrence| match Tag::parse(&occurrence.key) {
+            Some(Ok(Tag::Comment(text))) => Some((occurrence.span, text)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Byte offset of the first character in `range` of a body that is neither
+/// whitespace nor part of a comment
+pub fn first_text(body: &str, range: Range<usize>) -> Option<usize> {
+    let is_text = |c: char| !c.is_whitespace();
+    let mut cursor = range.start;
+    for (span, _) in comments(body) {
+        if span.start < cursor || span.end > range.end {
+            continue;
+        }
+        if let Some(i) = body[cursor..span.start].find(is_text) {
+            return Some(cursor + i);
+        }
+        cursor = span.end;
+    }
+    body[cursor..range.end].find(is_text).map(|i| cursor + i)
+}
+
+/// A `@[block name]@ ... @[endblock]@` section found by `blocks`
+#[derive(Debug)]
+pub struct BlockDef {
+    pub name: String,
+    /// Byte offset of the opening tag
+    pub offset: usize,
+    /// Byte range from the opening tag to the closing one, both included
+    pub span: Range<usize>,
+    /// Byte range of the content between the tags
+    pub inner: Range<usize>,
+    /// Number of blocks this one is nested in
+    pub depth: usize,
+}
+
+/// Find every block of a body, ordered by where they start
+pub fn blocks(body: &str) -> Result<Vec<BlockDef>, SyntaxError> {
+    let mut blocks: Vec<BlockDef> = Vec::new();
+    let mut open: Vec<(String, usize, Range<usize>)> = Vec::new();
+
+    for occurrence in placeholder::tokens(body) {
+        let offset = occurrence.span.start;
+        let span = tag_span(body, occurrence.span);
+        match Tag::parse(&occurrence.key) {
+            Some(Ok(Tag::Block(name))) => {
+                let defined = blocks.iter().map(|b| &b.name).chain(open.iter().map(|o| &o.0));
+                if defined.into_iter().any(|defined| *defined == name) {
+                    return Err(SyntaxError { offset, message: format!("block '{}' is defined twice", name) });
+                }
+                open.push((name, offset, span));
+            }
+            Some(Ok(Tag::EndBlock)) => {
+                let (name, open_offset, open_span) = open.pop().ok_or_else(|| SyntaxError {
+                    offset,
+                    message: "'@[endblock]@' without a matching '@[block]@'".into(),
+                })?;
+                blocks.push(BlockDef {
+                    name,
+                    offset: open_offset,
+                    span: open_span.start..span.end,
+                    inner: open_span.end..span.start,
+                    depth: open.len(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    if let Some((_, offset, _)) = open.pop() {
+        return Err(SyntaxError { offset, message: "'@[block]@' is never closed with '@[endblock]@'".into() });
+    }
+    blocks.sort_by_key(|block| block.span.start);
+    Ok(blocks)
+}
+
+/// Byte offsets and ranges of the `@[super]@` tags within `range` of a body
+pub fn super_tags(body: &str, range: Range<usize>) -> Vec<(usize, Range<usize>)> {
+    placeholder::tokens(body)
+        .into_iter()
+        .filter(|occurrence| range.contains(&occurrence.span.start))
+        .filter(|occurrence| matches!(Tag::parse(&occurrence.key), Some(Ok(Tag::Super))))
+        .map(|occurrence| (occurrence.span.start, tag_span(body, occurrence.span)))
+        .collect()
+}
+
+/// Reject `loop.` names outside loops and unknown `loop.` variables
+fn check_loop_variable(name: &str, in_loop: bool, offset: usize) -> Result<(), SyntaxError> {
+    if !name.starts_with(LOOP_PREFIX) {
+        return Ok(());
+    }
+    let message = if !is_loop_variable(name) {
+        let known: Vec<String> = LOOP_VARIABLES.iter()
+            .map(|(field, _)| format!("{}{}", LOOP_PREFIX, field))
+            .collect();
+        format!("unknown loop variable '{}' (expected one of {})", name, known.join(", "))
+    } else if !in_loop {
+        format!("'{}' used outside '@[for]@'", name)
+    } else {
+        return Ok(());
+    };
+    Err(SyntaxError { offset, message })
+}
+
+/// Names a body refers to that are not bound by one of its own loops
+#[derive(Debug, Default)]
+pub struct References {
+    /// Placeholder occurrences that take their value from the value map
+    pub placeholders: Vec<Occurrence>,
+    /// Names tested by `@[if]@` and `@[elif]@`
+    pub conditions: Vec<String>,
+    /// Lists iterated by `@[for]@`
+    pub lists: Vec<String>,
+    /// Names expressions can do without, such as the first argument of `default`
+    pub optional: Vec<String>,
+}
+
+/// Collect the names a body refers to. A body that does not parse is treated as plain text.
+pub fn references(body: &str) -> References {
+    let nodes = parse(body).unwrap_or_else(|_| vec![Node::Text(0..body.len())]);
+    let occurrences = placeholder::values(body);
+    let mut references = References::default();
+    collect_references(&nodes, &occurrences, &mut Vec::new(), &mut references);
+    references
+}
+
+fn collect_references<'a>(
+    nodes: &'a [Node],
+    occurrences: &[Occurrence],
+    bound: &mut Vec<&'a str>,
+    references: &mut References,
+) {
+    let is_free = |name: &str, bound: &[&str]| !bound.contains(&name) && !name.starts_with(LOOP_PREFIX);
+    let add = |names: &mut Vec<String>, name: &str| {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    };
+
+    for node in nodes {
+        match node {
+            Node::Text(range) => {
+                for occurrence in occurrences.iter().filter(|o| range.contains(&o.span.start)) {
+                    if !expr::is_expression(&occurrence.key) {
+                        if is_free(&occurrence.name, bound) {
+                            references.placeholders.push(occurrence.clone());
+                        }
+                        continue;
+                    }
+                    // Names an expression needs count as placeholders written where it is
+                    let Ok(expression) = Expr::parse(&occurrence.key) else {
+                        continue;
+                    };
+                    for (name, required) in expression.names() {
+                        if !is_free(&name, bound) {
+                            continue;
+                        }
+                        if required {
+                            references.placeholders.push(Occurrence { name, ..occurrence.clone() });
+                        } else {
+                            add(&mut references.optional, &name);
+                        }
+                    }
+                }
+            }
+            Node::If { branches, otherwise } => {
+                for (condition, nodes) in branches {
+                    if is_free(condition.name(), bound) {
+                        add(&mut references.conditions, condition.name());
+                    }
+                    collect_references(nodes, occurrences, bound, references);
+                }
+                collect_references(otherwise, occurrences, bound, references);
+            }
+            Node::For { var, list, body, .. } => {
+                if is_free(list, bound) {
+                    add(&mut references.lists, list);
+                }
+                bound.push(var);
+                collect_references(body, occurrences, bound, references);
+                bound.pop();
+            }
+            Node::Block(nodes) => collect_references(nodes, occurrences, bound, references),
+            Node::Include { .. } | Node::Raw(_) => {}
+        }
+    }
+}
+
+/// Source of the numbers in literal markers, unique across every template
+/// expanded by one invocation so rendered templates can be combined
+static NEXT_LITERAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Private-use characters around the number of a literal marker
+const LITERAL_MARKER: (char, char) = ('\u{e000}', '\u{e001}');
+
+/// A marker no other text of this invocation contains, to stand in for
+/// literal text while tron renders
+pub fn literal_marker() -> String {
+    let number = NEXT_LITERAL.fetch_add(1, Ordering::Relaxed);
+    format!("{}{}{}", LITERAL_MARKER.0, number, LITERAL_MARKER.1)
+}
+
+/// Body text left once blocks are resolved, with the body offset each run was copied from
+#[derive(Debug, Default)]
+pub struct Expansion {
+    pub body: String,
+    /// `(offset in the expansion, offset in the original body)` at the start of each run
+    pub origins: Vec<(usize, usize)>,
+    /// Markers in the body and the literal text each stands for
+    pub literals: Vec<(String, String)>,
+}
+
+impl Expansion {
+    /// Append `text`, which came from `origin` in the original body
+    fn push(&mut self, origin: usize, text: &str) {
+        if !text.is_empty() {
+            self.origins.push((self.body.len(), origin));
+            self.body.push_str(text);
+        }
+    }
+
+    /// Append body text, turning escaped delimiters into literals. The backslash
+    /// that escapes a delimiter is dropped; the ones before it are kept.
+    fn push_text(&mut self, origin: usize, text: &str) {
+        let mut cursor = 0;
+        for (i, _) in text.match_indices(OPEN).filter(|&(i, _)| placeholder::is_escaped(text, i)) {
+            self.push(origin + cursor, &text[cursor..i - 1]);
+            self.push_literal(origin + i, OPEN);
+            cursor = i + OPEN.len();
+        }
+        self.push(origin + cursor, &text[cursor..]);
+    }
+
+    /// Append text that must reach the output unchanged. tron could substitute
+    /// placeholder syntax in it, so a marker goes in its place until rendering is done.
+    fn push_literal(&mut self, origin: usize, text: &str) {
+        let marker = literal_marker();
+        self.push(origin, &marker);
+        self.literals.push((marker, text.to_string()));
+    }
+}
+
+/// Item of a list that a `@[for]@` is currently on
+struct Frame<'a> {
+    var: &'a str,
+    list: &'a str,
+    index: usize,
+    len: usize,
+}
+
+/// What a name stands for at some point in the body
+enum Binding {
+    /// The value stored under this key of the value map
+    Key(String),
+    /// A value computed during expansion, such as `loop.index`
+    Value(String),
+}
+
+/// Value map together with the loops enclosing the current node
+struct Scope<'a> {
+    values: &'a HashMap<String, String>,
+    frames: Vec<Frame<'a>>,
+}
+
+impl Scope<'_> {
+    fn bind(&self, name: &str) -> Binding {
+        if let (Some(field), Some(frame)) = (name.strip_prefix(LOOP_PREFIX), self.frames.last()) {
+            let value = match field {
+                "index" => frame.index.to_string(),
+                "first" => (frame.index == 0).to_string(),
+                _ => (frame.index + 1 == frame.len).to_string(),
+            };
+            return Binding::Value(value);
+        }
+        match self.frames.iter().rev().find(|frame| frame.var == name) {
+            Some(frame) => Binding::Key(list_key(frame.list, frame.index)),
+            None => Binding::Key(name.to_string()),
+        }
+    }
+
+    fn value(&self, name: &str) -> Option<String> {
+        match self.bind(name) {
+            Binding::Key(key) => self.values.get(&key).cloned(),
+            Binding::Value(value) => Some(value),
+        }
+    }
+
+    /// A list is true when it has items
+    fn is_truthy(&self, name: &str) -> bool {
+        match self.bind(name) {
+            Binding::Key(key) => {
+                is_truthy(self.values.get(&key).map(String::as_str)) || list_len(self.values, &key) > 0
+            }
+            Binding::Value(value) => is_truthy(Some(&value)),
+        }
+    }
+}
+
+/// Resolve blocks against the value map. Inside loops, placeholders naming the
+/// loop variable are rewritten to the key of the current item, and `loop.`
+/// variables are replaced by their value.
+pub fn expand(
+    nodes: &[Node],
+    body: &str,
+    values: &HashMap<String, String>,
+    out: &mut Expansion,
+) -> Result<(), SyntaxError> {
+    let occurrences = placeholder::values(body);
+    let mut scope = Scope { values, frames: Vec::new() };
+    expand_nodes(nodes, body, &occurrences, &mut scope, out)
+}
+
+fn expand_nodes<'a>(
+    nodes: &'a [Node],
+    body: &str,
+    occurrences: &[Occurrence],
+    scope: &mut Scope<'a>,
+    out: &mut Expansion,
+) -> Result<(), SyntaxError> {
+    for node in nodes {
+        match node {
+            Node::Text(range) if scope.frames.is_empty() => {
+                out.push_text(range.start, &body[range.clone()]);
+            }
+            Node::Text(range) => {
+                let mut cursor = range.start;
+                for occurrence in occurrences.iter().filter(|o| range.contains(&o.span.start)) {
+                    let replacement = if expr::is_expression(&occurrence.key) {
+                        let rewritten = expr::rewrite(&occurrence.key, |name| match scope.bind(name) {
+                            Binding::Key(key) if key == name => None,
+                            Binding::Key(key) => Some(key),
+                            Binding::Value(value) => Some(format!("\"{}\"", value)),
+                        });
+                        if rewritten == occurrence.key {
+                            continue;
+                        }
+                        let token = &body[occurrence.span.clone()];
+                        token.replacen(&occurrence.key, &rewritten, 1)
+                    } else {
+                        match scope.bind(&occurrence.name) {
+                            Binding::Key(key) if key == occurrence.name => continue,
+                            Binding::Key(key) => {
+                                let token = &body[occurrence.span.clone()];
+                                let at = token.find(&occurrence.name).unwrap();
+                                format!("{}{}{}", &token[..at], key, &token[at + occurrence.name.len()..])
+                            }
+                            Binding::Value(value) => occurrence.filters.iter()
+                                .fold(value, |value, filter| filters::apply(filter, &value)),
+                        }
+                    };
+                    out.push_text(cursor, &body[cursor..occurrence.span.start]);
+                    out.push(occurrence.span.start, &replacement);
+                    cursor = occurrence.span.end;
+                }
+                out.push_text(cursor, &body[cursor..range.end]);
+            }
+            Node::If { branches, otherwise } => {
+                let chosen = branches.iter()
+                    .find(|(condition, _)| condition.eval(scope))
+                    .map_or(otherwise, |(_, nodes)| nodes);
+                expand_nodes(chosen, body, occurrences, scope, out)?;
+            }
+            Node::For { var, list, offset, body: nodes } => {
+                let len = list_len(scope.values, list);
+                if len == 0 && scope.values.contains_key(list) {
+                    return Err(SyntaxError {
+                        offset: *offset,
+                        message: format!(
+                            "'{}' is a single value, not a list (supply items with -v {}[]=...)",
+                            list, list
+                        ),
+                    });
+                }
+                for index in 0..len {
+                    scope.frames.push(Frame { var, list, index, len });
+                    expand_nodes(nodes, body, occurrences, scope, out)?;
+                    scope.frames.pop();
+                }
+            }
+            Node::Block(nodes) => expand_nodes(nodes, body, occurrences, scope, out)?,
+            Node::Raw(range) => out.push_literal(range.start, &body[range.clone()]),
+            Node::Include { offset, .. } => {
+                return Err(SyntaxError {
+                    offset: *offset,
+                    message: "partial was not included before rendering".into(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(body: &str) -> (usize, String) {
+        let error = parse(body).unwrap_err();
+        (error.offset, error.message)
+    }
+
+    /// Expand `body` and put back the literal text its markers stand for
+    fn expanded(body: &str, values: &[(&str, &str)]) -> String {
+        let values: HashMap<String, String> = values.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let nodes = parse(body).unwrap();
+        let mut expansion = Expansion::default();
+        expand(&nodes, body, &values, &mut expansion).unwrap();
+        expansion.literals.iter().fold(expansion.body, |text, (marker, literal)| text.replace(marker, literal))
+    }
+
+    #[test]
+    fn unbalanced_blocks() {
+        assert_eq!(parse_error("a @[if x]@ b"), (2, "'@[if]@' is never closed with '@[endif]@'".into()));
+        assert_eq!(parse_error("@[if x]@@[if y]@@[endif]@"), (0, "'@[if]@' is never closed with '@[endif]@'".into()));
+        assert_eq!(parse_error("ab @[endif]@"), (3, "'@[endif]@' without a matching '@[if]@'".into()));
+        assert_eq!(parse_error("@[for f in fs]@"), (0, "'@[for]@' is never closed with '@[endfor]@'".into()));
+        assert_eq!(parse_error("@[endfor]@"), (0, "'@[endfor]@' without a matching '@[for]@'".into()));
+        assert_eq!(parse_error("@[for f in fs]@\n@[if x]@\n@[endfor]@"), (
+            25, "'@[endfor]@' inside '@[if]@', which is still open".into()
+        ));
+        assert_eq!(parse_error("@[raw]@ text"), (0, "'@[raw]@' is never closed with '@[endraw]@'".into()));
+        assert_eq!(parse_error("@[endraw]@"), (0, "'@[endraw]@' without a matching '@[raw]@'".into()));
+    }
+
+    #[test]
+    fn misplaced_branches() {
+        assert_eq!(parse_error("x @[else]@"), (2, "'@[else]@' without a matching '@[if]@'".into()));
+        assert_eq!(parse_error("@[elif y]@"), (0, "'@[elif]@' without a matching '@[if]@'".into()));
+        assert_eq!(parse_error("@[if x]@a@[else]@b@[else]@c@[endif]@"), (18, "'@[else]@' after '@[else]@'".into()));
+        assert_eq!(parse_error("@[if x]@a@[else]@b@[elif y]@c@[endif]@"), (18, "'@[elif]@' after '@[else]@'".into()));
+        assert_eq!(parse_error("@[for f in fs]@@[else]@@[endfor]@"), (
+            15, "'@[else]@' inside '@[for]@', which is still open".into()
+        ));
+    }
+
+    #[test]
+    fn malformed_tags() {
+        assert_eq!(parse_error("a\n@[else x]@"), (2, "unexpected 'x' after 'else'".into()));
+        assert_eq!(parse_error("@[if x]@@[endif now]@"), (8, "unexpected 'now' after 'endif'".into()));
+        assert_eq!(parse_error("a\n@[for f fs]@"), (2, "expected 'for <name> in <list>'".into()));
+        assert_eq!(parse_error("@[for loop in fs]@@[endfor]@"), (0, "'loop' is reserved for the loop variables".into()));
+        assert_eq!(parse_error("@[loop.index]@"), (0, "'loop.index' used outside '@[for]@'".into()));
+        assert_eq!(parse_error("@[for f in fs]@@[loop.size]@@[endfor]@"), (
+            15, "unknown loop variable 'loop.size' (expected one of loop.index, loop.first, loop.last)".into()
+        ));
+    }
+
+    #[test]
+    fn expression_errors_point_into_the_key() {
+        assert_eq!(parse_error("a @[ = 1 + ]@"), (10, "expected a value, name or '('".into()));
+        assert_eq!(parse_error("@[= len(loop.index)]@"), (0, "'loop.index' used outside '@[for]@'".into()));
+    }
+
+    #[test]
+    fn expands_the_first_true_branch() {
+        let body = "@[if a]@A@[elif b]@B@[else]@C@[endif]@|@[if not a]@@[name]@@[endif]@";
+        assert_eq!(expanded(body, &[("a", "true")]), "A|");
+        assert_eq!(expanded(body, &[("a", "off"), ("b", "yes")]), "B|@[name]@");
+        assert_eq!(expanded(body, &[]), "C|@[name]@");
+    }
+
+    #[test]
+    fn expands_loops_over_list_items() {
+        let body = "@[for f in fs]@@[f|upper]@@[if not loop.last]@,@[endif]@@[endfor]@|@[for f in none]@x@[endfor]@";
+        assert_eq!(expanded(body, &[("fs.0", "x"), ("fs.1", "y")]), "@[fs.0|upper]@,@[fs.1|upper]@|");
+        assert_eq!(expanded("@[for f in fs]@@[loop.index]@@[endfor]@", &[("fs.0", "x"), ("fs.1", "y")]), "01");
+    }
+
+    #[test]
+    fn raw_blocks_and_escapes_stay_literal() {
+        assert_eq!(expanded("\\@[a]@ @[b]@", &[]), "@[a]@ @[b]@");
+        assert_eq!(expanded("@[raw]@@[a]@ @[if]@ \\@[b]@@[endraw]@", &[]), "@[a]@ @[if]@ \\@[b]@");
+        assert_eq!(expanded("@[if x]@@[raw]@@[a]@@[endraw]@@[endif]@", &[("x", "1")]), "@[a]@");
+        assert_eq!(expanded("@[if x]@@[raw]@@[a]@@[endraw]@@[endif]@", &[]), "");
+    }
+
+    #[test]
+    fn backslashes_before_a_delimiter_pair_up() {
+        assert_eq!(expanded("\"C:\\\\@[dir]@\"", &[]), "\"C:\\\\@[dir]@\"");
+        assert_eq!(expanded("\\\\\\@[a]@", &[]), "\\\\@[a]@");
+        assert_eq!(expanded("\\\\\\\\@[a]@", &[]), "\\\\\\\\@[a]@");
+        let keys: Vec<String> = placeholder::tokens("\\\\@[a]@ \\\\\\@[b]@").into_iter().map(|o| o.key).collect();
+        assert_eq!(keys, ["a"]);
+    }
+
+    #[test]
+    fn standalone_tags_take_their_line() {
+        assert_eq!(expanded("a\n  @[if x]@\nb\n@[endif]@\nc\n", &[("x", "1")]), "a\nb\nc\n");
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::filters;
+use crate::values::{list_key, list_len};
+
+/// First character of a placeholder that holds an expression, as in `@[= len(fields)]@`
+pub const EXPRESSION_PREFIX: char = '=';
+
+/// Functions callable in expressions besides the filters, with a short description of each
+pub const FUNCTIONS: &[(&str, &str)] = &[
+    ("len", "number of items in a list, or of characters in a value"),
+    ("join", "items of a list with a separator between them: join(list, \", \")"),
+    ("default", "the first argument, or the second when the first is unset or empty"),
+];
+
+/// Whether a placeholder key is an expression rather than a placeholder name
+pub fn is_expression(key: &str) -> bool {
+    key.starts_with(EXPRESSION_PREFIX)
+}
+
+/// Problem in an expression, at a byte offset of the placeholder key
+#[derive(Debug)]
+pub struct ExprError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl ExprError {
+    fn new(offset: usize, message: String) -> Self {
+        Self { offset, message }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Text(String),
+    Name(String),
+    Operator(char),
+    Open,
+    Close,
+    Comma,
+}
+
+/// Split an expression key into tokens with their byte ranges, skipping the prefix
+fn lex(key: &str) -> Result<Vec<(Range<usize>, Token)>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut chars = key.char_indices().skip(1).peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            '+' | '-' | '*' | '/' | '%' | '~' => Token::Operator(c),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((at, '\\')) => match chars.next() {
+                            Some((_, 'n')) => text.push('\n'),
+                            Some((_, 't')) => text.push('\t'),
+                            Some((_, c @ ('"' | '\\'))) => text.push(c),
+                            _ => return Err(ExprError::new(at, "unknown escape in string".into())),
+                        },
+                        Some((_, c)) => text.push(c),
+                        None => return Err(ExprError::new(start, "string is never closed".into())),
+                    }
+                }
+                Token::Text(text)
+            }
+            c if c.is_ascii_digit() => {
+                let mut end = start + 1;
+                while let Some(&(at, c)) = chars.peek().filter(|(_, c)| c.is_ascii_digit()) {
+                    end = at + c.len_utf8();
+                    chars.next();
+                }
+                let number = key[start..end].parse()
+                    .map_err(|_| ExprError::new(start, format!("number '{}' is too large", &key[start..end])))?;
+                Token::Number(number)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(at, c)) = chars.peek().filter(|(_, c)| c.is_alphanumeric() || matches!(c, '_' | '.')) {
+                    end = at + c.len_utf8();
+                    chars.next();
+                }
+                Token::Name(key[start..end].to_string())
+            }
+            c => return Err(ExprError::new(start, format!("unexpected '{}'", c))),
+        };
+        let end = chars.peek().map_or(key.len(), |&(at, _)| at);
+        tokens.push((start..end, token));
+    }
+    Ok(tokens)
+}
+
+/// Parsed expression
+#[derive(Debug)]
+pub enum Expr {
+    Number { number: i64, offset: usize },
+    Text { text: String, offset: usize },
+    /// A value from the value map; a list when it names one
+    Name { name: String, offset: usize },
+    Call { function: String, args: Vec<Expr>, offset: usize },
+    Binary { operator: char, left: Box<Expr>, right: Box<Expr>, offset: usize },
+    Negate { operand: Box<Expr>, offset: usize },
+}
+
+/// Recursive descent over the tokens of one expression
+struct Parser {
+    tokens: Vec<(Range<usize>, Token)>,
+    position: usize,
+    /// Offset reported for problems at the end of the expression
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.position).map_or(self.end, |(range, _)| range.start)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token, what: &str) -> Result<(), ExprError> {
+        let offset = self.offset();
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            _ => Err(ExprError::new(offset, format!("expected {}", what))),
+        }
+    }
+
+    /// Binary operators in `operators`, left to right, over operands read by `operand`
+    fn binary(
+        &mut self,
+        operators: &[char],
+        operand: fn(&mut Self) -> Result<Expr, ExprError>,
+    ) -> Result<Expr, ExprError> {
+        let mut left = operand(self)?;
+        while let Some(&Token::Operator(operator)) = self.peek().filter(|token| {
+            matches!(token, Token::Operator(c) if operators.contains(c))
+        }) {
+            let offset = self.offset();
+            self.next();
+            let right = operand(self)?;
+            left = Expr::Binary { operator, left: Box::new(left), right: Box::new(right), offset };
+        }
+        Ok(left)
+    }
+
+    /// `~` binds loosest, so `name ~ count + 1` concatenates a sum
+    fn concat(&mut self) -> Result<Expr, ExprError> {
+        self.binary(&['~'], Self::sum)
+    }
+
+    fn sum(&mut self) -> Result<Expr, ExprError> {
+        self.binary(&['+', '-'], Self::product)
+    }
+
+    fn product(&mut self) -> Result<Expr, ExprError> {
+        self.binary(&['*', '/', '%'], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        if self.peek() == Some(&Token::Operator('-')) {
+            let offset = self.offset();
+            self.next();
+            return Ok(Expr::Negate { operand: Box::new(self.unary()?), offset });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExprError> {
+        let offset = self.offset();
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number { number, offset }),
+            Some(Token::Text(text)) => Ok(Expr::Text { text, offset }),
+            Some(Token::Open) => {
+                let inner = self.concat()?;
+                self.expect(Token::Close, "')'")?;
+                Ok(inner)
+            }
+            Some(Token::Name(name)) if self.peek() == Some(&Token::Open) => {
+                self.next();
+                let mut args = Vec::new();
+                if self.peek() != Some(&Token::Close) {
+                    args.push(self.concat()?);
+                    while self.peek() == Some(&Token::Comma) {
+                        self.next();
+                        args.push(self.concat()?);
+                    }
+                }
+                self.expect(Token::Close, "',' or ')' after function arguments")?;
+                check_call(&name, args.len()).map_err(|message| ExprError::new(offset, message))?;
+                Ok(Expr::Call { function: name, args, offset })
+            }
+            Some(Token::Name(name)) => Ok(Expr::Name { name, offset }),
+            _ => Err(ExprError::new(offset, "expected a value, name or '('".into())),
+        }
+    }
+}
+
+/// Reject unknown functions and calls with the wrong number of arguments
+fn check_call(function: &str, count: usize) -> Result<(), String> {
+    let expected = match function {
+        "len" => 1,
+        "join" | "default" => 2,
+        _ if filters::is_known(function) => 1,
+        _ => {
+            let known: Vec<&str> = FUNCTIONS.iter().map(|(name, _)| *name).collect();
+            return Err(format!("unknown function '{}' (expected {} or a filter)", function, known.join(", ")));
+        }
+    };
+    if count == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "'{}' takes {} argument{}, not {}",
+            function, expected, if expected == 1 { "" } else { "s" }, count
+        ))
+    }
+}
+
+/// Value of an expression or one of its parts
+enum Value {
+    Text(String),
+    List(Vec<String>),
+    /// A name with no value, kept so `default`, `len` and `join` can accept it
+    Missing(String),
+}
+
+/// Where expressions look up the names they use
+pub struct Lookup<'a> {
+    pub values: &'a HashMap<String, String>,
+    /// Defaults declared by placeholders of the same name
+    pub defaults: &'a HashMap<String, String>,
+}
+
+impl Lookup<'_> {
+    fn get(&self, name: &str) -> Value {
+        if let Some(value) = self.values.get(name).filter(|value| !value.is_empty()) {
+            return Value::Text(value.clone());
+        }
+        let len = list_len(self.values, name);
+        if len > 0 {
+            return Value::List((0..len).map(|i| self.values[&list_key(name, i)].clone()).collect());
+        }
+        match self.defaults.get(name) {
+            Some(default) => Value::Text(default.clone()),
+            None => Value::Missing(name.to_string()),
+        }
+    }
+}
+
+impl Expr {
+    /// Parse the key of an expression placeholder, `=` included
+    pub fn parse(key: &str) -> Result<Self, ExprError> {
+        let mut parser = Parser { tokens: lex(key)?, position: 0, end: key.len() };
+        let expr = parser.concat()?;
+        if parser.position < parser.tokens.len() {
+            return Err(ExprError::new(parser.offset(), "expected an operator or the end of the expression".into()));
+        }
+        Ok(expr)
+    }
+
+    /// Names the expression uses, each with whether it needs a value. Names
+    /// given straight to `len`, `join` or as the first argument of `default`
+    /// may be unset.
+    pub fn names(&self) -> Vec<(String, bool)> {
+        let mut names = Vec::new();
+        self.collect_names(true, &mut names);
+        names
+    }
+
+    fn collect_names(&self, required: bool, names: &mut Vec<(String, bool)>) {
+        match self {
+            Expr::Number { .. } | Expr::Text { .. } => {}
+            Expr::Name { name, .. } => names.push((name.clone(), required)),
+            Expr::Call { function, args, .. } => {
+                for (i, arg) in args.iter().enumerate() {
+                    let optional = matches!((function.as_str(), i), ("len", 0) | ("join", 0) | ("default", 0));
+                    arg.collect_names(!optional, names);
+                }
+            }
+            Expr::Binary { left, right, .. } => {
+                left.collect_names(true, names);
+                right.collect_names(true, names);
+            }
+            Expr::Negate { operand, .. } => operand.collect_names(true, names),
+        }
+    }
+
+    /// Evaluate to the text tron sets for the placeholder
+    pub fn eval(&self, lookup: &Lookup) -> Result<String, ExprError> {
+        let value = self.value(lookup)?;
+        text(value, self.offset())
+    }
+
+    /// Byte offset of the expression in the key, for errors about its value
+    fn offset(&self) -> usize {
+        match self {
+            Expr::Number { offset, .. }
+            | Expr::Text { offset, .. }
+            | Expr::Name { offset, .. }
+            | Expr::Call { offset, .. }
+            | Expr::Binary { offset, .. }
+            | Expr::Negate { offset, .. } => *offset,
+        }
+    }
+
+    fn value(&self, lookup: &Lookup) -> Result<Value, ExprError> {
+        let value = match self {
+            Expr::Number { number, .. } => Value::Text(number.to_string()),
+            Expr::Text { text, .. } => Value::Text(text.clone()),
+            Expr::Name { name, .. } => lookup.get(name),
+            Expr::Call { function, args, .. } => {
+                let mut values = Vec::new();
+                for arg in args {
+                    values.push((arg.value(lookup)?, arg.offset()));
+                }
+                call(function, values)?
+            }
+            Expr::Negate { operand, offset } => {
+                let number = self::number(operand.value(lookup)?, operand.offset())?;
+                let negated = number.checked_neg()
+                    .ok_or_else(|| ExprError::new(*offset, "arithmetic overflow".into()))?;
+                Value::Text(negated.to_string())
+            }
+            Expr::Binary { operator: '~', left, right, .. } => {
+                let left = text(left.value(lookup)?, left.offset())?;
+                Value::Text(left + &text(right.value(lookup)?, right.offset())?)
+            }
+            Expr::Binary { operator, left, right, offset } => {
+                let left = number(left.value(lookup)?, left.offset())?;
+                let right = number(right.value(lookup)?, right.offset())?;
+                let result = match operator {
+                    '+' => left.checked_add(right),
+                    '-' => left.checked_sub(right),
+                    '*' => left.checked_mul(right),
+                    _ if right == 0 => return Err(ExprError::new(*offset, "division by zero".into())),
+                    '/' => left.checked_div(right),
+                    _ => left.checked_rem(right),
+                };
+                let result = result.ok_or_else(|| ExprError::new(*offset, "arithmetic overflow".into()))?;
+                Value::Text(result.to_string())
+            }
+        };
+        Ok(value)
+    }
+}
+
+/// Apply a function to its evaluated arguments, each with its offset.
+/// `parse` has checked the number of arguments.
+fn call(function: &str, mut args: Vec<(Value, usize)>) -> Result<Value, ExprError> {
+    let (first, first_offset) = args.remove(0);
+    let value = match function {
+        "default" => match first {
+            Value::Missing(_) => args.remove(0).0,
+            Value::Text(text) if text.is_empty() => args.remove(0).0,
+            value => value,
+        },
+        "len" => {
+            let len = match first {
+                Value::List(items) => items.len(),
+                Value::Text(text) => text.chars().count(),
+                Value::Missing(_) => 0,
+            };
+            Value::Text(len.to_string())
+        }
+        "join" => {
+            let (separator, separator_offset) = args.remove(0);
+            let separator = text(separator, separator_offset)?;
+            match first {
+                Value::List(items) => Value::Text(items.join(&separator)),
+                Value::Text(text) => Value::Text(text),
+                Value::Missing(_) => Value::Text(String::new()),
+            }
+        }
+        filter => Value::Text(filters::apply(filter, &text(first, first_offset)?)),
+    };
+    Ok(value)
+}
+
+/// A value used as text; lists and missing values are errors
+fn text(value: Value, offset: usize) -> Result<String, ExprError> {
+    match value {
+        Value::Text(text) => Ok(text),
+        Value::List(_) => Err(ExprError::new(offset, "a list cannot be used as a value (use join or len)".into())),
+        Value::Missing(name) => Err(ExprError::new(offset, format!("no value for '{}'", name))),
+    }
+}
+
+/// A value used in arithmetic
+fn number(value: Value, offset: usize) -> Result<i64, ExprError> {
+    let text = text(value, offset)?;
+    text.trim().parse().map_err(|_| ExprError::new(offset, format!("'{}' is not a whole number", text)))
+}
+
+/// Replace the names in an expression key, leaving function names alone.
+/// `replace` returns the new text for a name, or `None` to keep it.
+pub fn rewrite(key: &str, replace: impl Fn(&str) -> Option<String>) -> String {
+    let Ok(tokens) = lex(key) else {
+        return key.to_string();
+    };
+    let mut rewritten = String::new();
+    let mut cursor = 0;
+    for (i, (range, token)) in tokens.iter().enumerate() {
+        let Token::Name(name) = token else {
+            continue;
+        };
+        if tokens.get(i + 1).is_some_and(|(_, next)| *next == Token::Open) {
+            continue;
+        }
+        if let Some(replacement) = replace(name) {
+            rewritten.push_str(&key[cursor..range.start]);
+            rewritten.push_str(&replacement);
+            cursor = range.end;
+        }
+    }
+    rewritten.push_str(&key[cursor..]);
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_with(key: &str, values: &[(&str, &str)]) -> Result<String, ExprError> {
+        let values: HashMap<String, String> = values.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Expr::parse(key)?.eval(&Lookup { values: &values, defaults: &HashMap::new() })
+    }
+
+    fn eval(key: &str) -> String {
+        eval_with(key, &[]).unwrap()
+    }
+
+    fn error(key: &str, values: &[(&str, &str)]) -> (usize, String) {
+        let error = eval_with(key, values).unwrap_err();
+        (error.offset, error.message)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("= 1 + 2 * 3"), "7");
+        assert_eq!(eval("= (1 + 2) * 3"), "9");
+        assert_eq!(eval("= 10 - 4 - 3"), "3");
+        assert_eq!(eval("= 7 % 4 * 2"), "6");
+        assert_eq!(eval("= -2 * -3"), "6");
+        assert_eq!(eval("= \"v\" ~ 1 + 2 * 3"), "v7");
+        assert_eq!(eval("= 1 ~ 2 + 3"), "15");
+    }
+
+    #[test]
+    fn functions_accept_missing_names() {
+        assert_eq!(eval("= default(name, \"x\")"), "x");
+        assert_eq!(eval("= len(fields)"), "0");
+        assert_eq!(eval("= join(fields, \", \")"), "");
+        assert_eq!(eval_with("= default(name, \"x\")", &[("name", "")]).unwrap(), "x");
+        assert_eq!(eval_with("= default(name, \"x\")", &[("name", "y")]).unwrap(), "y");
+        assert_eq!(
+            eval_with("= join(fields, \", \") ~ \"/\" ~ len(fields)", &[("fields.0", "a"), ("fields.1", "b")]).unwrap(),
+            "a, b/2"
+        );
+        assert_eq!(eval_with("= upper(name)", &[("name", "ab")]).unwrap(), "AB");
+        assert_eq!(error("= upper(name)", &[]), (8, "no value for 'name'".into()));
+        assert_eq!(error("= name ~ \"s\"", &[("name.0", "a")]).1, "a list cannot be used as a value (use join or len)");
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        assert_eq!(error("= 1 / 0", &[]), (4, "division by zero".into()));
+        assert_eq!(error("= 1 % (2 - 2)", &[]), (4, "division by zero".into()));
+        assert_eq!(error("= 9223372036854775807 + 1", &[]), (22, "arithmetic overflow".into()));
+        assert_eq!(error("= n * 2", &[("n", "4611686018427387904")]), (4, "arithmetic overflow".into()));
+        assert_eq!(error("= -n", &[("n", "-9223372036854775808")]), (2, "arithmetic overflow".into()));
+        assert_eq!(error("= 99999999999999999999", &[]), (2, "number '99999999999999999999' is too large".into()));
+        assert_eq!(error("= n + 1", &[("n", "x")]), (2, "'x' is not a whole number".into()));
+    }
+
+    #[test]
+    fn parse_error_offsets() {
+        let parse_error = |key: &str| {
+            let error = Expr::parse(key).unwrap_err();
+            (error.offset, error.message)
+        };
+        assert_eq!(parse_error("= 1 +"), (5, "expected a value, name or '('".into()));
+        assert_eq!(parse_error("= (1 + 2"), (8, "expected ')'".into()));
+        assert_eq!(parse_error("= 1 2"), (4, "expected an operator or the end of the expression".into()));
+        assert_eq!(parse_error("= a # b"), (4, "unexpected '#'".into()));
+        assert_eq!(parse_error("= \"abc"), (2, "string is never closed".into()));
+        assert_eq!(parse_error("= \"a\\qb\""), (4, "unknown escape in string".into()));
+        assert_eq!(parse_error("= len(a, b)"), (2, "'len' takes 1 argument, not 2".into()));
+        assert_eq!(parse_error("= x ~ join(a)"), (6, "'join' takes 2 arguments, not 1".into()));
+        assert_eq!(
+            parse_error("= foo(a)"),
+            (2, "unknown function 'foo' (expected len, join, default or a filter)".into())
+        );
+        assert_eq!(parse_error("= len(a b)"), (8, "expected ',' or ')' after function arguments".into()));
+    }
+
+    #[test]
+    fn names_and_whether_they_are_required() {
+        let names = Expr::parse("= default(a, b) ~ len(c) ~ join(d, e) ~ upper(f) ~ g").unwrap().names();
+        assert_eq!(names, [
+            ("a".to_string(), false),
+            ("b".to_string(), true),
+            ("c".to_string(), false),
+            ("d".to_string(), false),
+            ("e".to_string(), true),
+            ("f".to_string(), true),
+            ("g".to_string(), true),
+        ]);
+    }
+
+    #[test]
+    fn rewrite_leaves_function_names_alone() {
+        let replace = |name: &str| (name == "len" || name == "f").then(|| "fields.0".to_string());
+        assert_eq!(rewrite("= len(len) ~ f", replace), "= len(fields.0) ~ fields.0");
+        assert_eq!(rewrite("= upper( f )", replace), "= upper( fields.0 )");
+        assert_eq!(rewrite("= \"f\" ~ g", replace), "= \"f\" ~ g");
+        assert_eq!(rewrite("= f ~ \"", replace), "= f ~ \"");
+    }
+}
@@ -0,0 +1,57 @@
+use heck::{ToKebabCase, ToLowerCamelCase, ToShoutySnakeCase, ToSnakeCase, ToUpperCamelCase};
+
+/// Built-in filters usable as `@[name|filter]@`, with a short description of each
+pub const FILTERS: &[(&str, &str)] = &[
+    ("raw", "insert the value unchanged"),
+    ("rust_str", "escape for use inside a Rust string literal"),
+    ("quoted", "escape and wrap in double quotes as a Rust string literal"),
+    ("snake_case", "convert to snake_case"),
+    ("pascal_case", "convert to PascalCase"),
+    ("camel_case", "convert to camelCase"),
+    ("screaming_snake_case", "convert to SCREAMING_SNAKE_CASE"),
+    ("kebab_case", "convert to kebab-case"),
+    ("lower", "convert to lowercase"),
+    ("upper", "convert to uppercase"),
+    ("trim", "remove leading and trailing whitespace"),
+];
+
+/// Whether `name` is one of the built-in filters
+pub fn is_known(name: &str) -> bool {
+    FILTERS.iter().any(|(filter, _)| *filter == name)
+}
+
+/// Apply a built-in filter to a value. Unknown filters are rejected when the
+/// template is loaded, so they never reach this point.
+pub fn apply(filter: &str, value: &str) -> String {
+    match filter {
+        "rust_str" => escape_rust_str(value),
+        "quoted" => format!("\"{}\"", escape_rust_str(value)),
+        "snake_case" => value.to_snake_case(),
+        "pascal_case" => value.to_upper_camel_case(),
+        "camel_case" => value.to_lower_camel_case(),
+        "screaming_snake_case" => value.to_shouty_snake_case(),
+        "kebab_case" => value.to_kebab_case(),
+        "lower" => value.to_lowercase(),
+        "upper" => value.to_uppercase(),
+        "trim" => value.trim().to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Escape a value so it can sit between the quotes of a Rust string literal
+fn escape_rust_str(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\0' => escaped.push_str("\\0"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,9 +1,30 @@
-use clap::{Parser, Subcommand};
-use tron::{TronTemplate, TronRef, TronAssembler, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tron::{TronTemplate, TronRef, TronAssembler, TronError, Result};
 use std::collections::HashMap;
-use std::fs;
-use std::path::PathBuf;
-use tokio;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+mod engine;
+mod expr;
+mod filters;
+mod placeholder;
+mod project;
+mod prompt;
+mod rust;
+mod sourcemap;
+mod stdio;
+mod template;
+mod types;
+mod values;
+
+use placeholder::Placeholder;
+use project::Project;
+use sourcemap::SourceMap;
+use template::{Template, TronValues};
+use values::{
+    list_name, load_env_values, load_values_file, parse_dependency, parse_key_values, DuplicatePolicy, ValueSource,
+};
 
 #[derive(Parser)]
 #[command(author, version, about = "CLI tool for managing Rust templates")]
@@ -16,7 +37,7 @@ struct Cli {
 enum Commands {
     /// Create a new template file
     New {
-        /// Path to save the template
+        /// Path to save the template ('-' for stdout)
         #[arg(short, long)]
         output: PathBuf,
         
@@ -24,78 +45,449 @@ enum Commands {
         #[arg(short, long)]
         content: Option<String>,
         
-        /// Template content from file
+        /// Template content from file ('-' for stdin)
         #[arg(short, long)]
         file: Option<PathBuf>,
     },
     
     /// Render a template with provided values
     Render {
-        /// Path to template file
+        /// Path to template file ('-' for stdin)
         #[arg(short, long)]
         template: PathBuf,
+
+        #[command(flatten)]
+        includes: IncludeArgs,
         
-        /// Key-value pairs for template placeholders (format: key=value)
-        #[arg(short, long)]
-        values: Vec<String>,
+        #[command(flatten)]
+        values: ValueArgs,
         
-        /// Output path for rendered content
+        /// Output path for rendered content ('-' for stdout, the default)
         #[arg(short, long)]
         output: Option<PathBuf>,
+
+        /// Fail if any placeholder is left unset or any supplied key matches nothing
+        /// (on by default when the CI environment variable is set)
+        #[arg(long, overrides_with = "no_strict")]
+        strict: bool,
+
+        /// Disable strict mode, even under CI
+        #[arg(long)]
+        no_strict: bool,
+
+        /// Pretty-print the rendered Rust code (comments are not preserved)
+        #[arg(long)]
+        format: bool,
+
+        /// Fail if the rendered output does not parse as Rust
+        #[arg(long)]
+        check: bool,
+
+        /// Write a JSON source map tracing the output back to the template
+        #[arg(long, value_name = "PATH", conflicts_with = "format")]
+        source_map: Option<PathBuf>,
+
+        /// Never prompt for missing values, even on a terminal
+        #[arg(long)]
+        no_input: bool,
     },
     
     /// Execute a template using rust-script
     Execute {
-        /// Path to template file
+        /// Path to template file ('-' for stdin)
         #[arg(short, long)]
         template: PathBuf,
+
+        #[command(flatten)]
+        includes: IncludeArgs,
         
-        /// Key-value pairs for template placeholders (format: key=value)
-        #[arg(short, long)]
-        values: Vec<String>,
+        #[command(flatten)]
+        values: ValueArgs,
         
         /// Dependencies to include (format: name=version)
         #[arg(short, long)]
         dependencies: Vec<String>,
+
+        /// Never prompt for missing values, even on a terminal
+        #[arg(long)]
+        no_input: bool,
     },
     
     /// Combine multiple templates
     Assemble {
-        /// Paths to template files
+        /// Paths to template files (one may be '-' for stdin)
         #[arg(short, long)]
         templates: Vec<PathBuf>,
+
+        #[command(flatten)]
+        includes: IncludeArgs,
         
-        /// Global key-value pairs for template placeholders
-        #[arg(short, long)]
-        values: Vec<String>,
+        #[command(flatten)]
+        values: ValueArgs,
         
-        /// Output path for combined template
+        /// Output path for combined template ('-' for stdout)
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Pretty-print the combined Rust code (comments are not preserved)
+        #[arg(long)]
+        format: bool,
+
+        /// Fail if the combined output does not parse as Rust
+        #[arg(long)]
+        check: bool,
+
+        /// Write a JSON source map tracing the output back to the templates
+        #[arg(long, value_name = "PATH", conflicts_with = "format")]
+        source_map: Option<PathBuf>,
+    },
+
+    /// Generate a tree of files from a template directory
+    Generate {
+        /// Template directory: `.tmrs` files are rendered without their extension,
+        /// other files are copied, and placeholders in names are filled in
+        #[arg(short, long)]
+        template: PathBuf,
+
+        /// Directory to generate into, created if missing
         #[arg(short, long)]
         output: PathBuf,
+
+        #[command(flatten)]
+        includes: IncludeArgs,
+
+        #[command(flatten)]
+        values: ValueArgs,
+
+        /// Replace files that already exist in the output directory
+        #[arg(long)]
+        force: bool,
+
+        /// Fail if any placeholder is left unset or any supplied key matches nothing
+        /// (on by default when the CI environment variable is set)
+        #[arg(long, overrides_with = "no_strict")]
+        strict: bool,
+
+        /// Disable strict mode, even under CI
+        #[arg(long)]
+        no_strict: bool,
+
+        /// Pretty-print generated `.rs` files (comments are not preserved)
+        #[arg(long)]
+        format: bool,
+
+        /// Never prompt for missing values, even on a terminal
+        #[arg(long)]
+        no_input: bool,
+    },
+
+    /// List the placeholders used in a template
+    Inspect {
+        /// Path to template file ('-' for stdin)
+        #[arg(short, long)]
+        template: PathBuf,
+
+        #[command(flatten)]
+        includes: IncludeArgs,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+    },
+
+    /// Translate a line:column of rendered output back to its template
+    Locate {
+        /// Source map written by render or assemble with --source-map
+        #[arg(short, long)]
+        map: PathBuf,
+
+        /// Position in the rendered output, as LINE or LINE:COLUMN
+        position: String,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
     },
 }
 
-/// Parse key-value pairs from command line arguments
-fn parse_key_values(pairs: &[String]) -> HashMap<String, String> {
-    pairs.iter()
-        .filter_map(|pair| {
-            let mut parts = pair.splitn(2, '=');
-            match (parts.next(), parts.next()) {
-                (Some(key), Some(value)) => Some((key.to_string(), value.to_string())),
-                _ => None,
+/// Output format for reports printed by the CLI
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    Text,
+    Json,
+}
+
+/// Where `@[include]@` partials are looked up, shared by the commands that load templates
+#[derive(Args)]
+struct IncludeArgs {
+    /// Directory to search for included partials, after the including template's
+    /// own directory (may be repeated)
+    #[arg(short = 'I', long = "include-dir", value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+}
+
+impl IncludeArgs {
+    /// Load a template and splice in its partials
+    fn load(&self, path: &Path) -> Result<Template> {
+        Template::load(path)?.compose(&self.include_dirs)
+    }
+}
+
+/// Placeholder value sources shared by render, execute and assemble
+#[derive(Args)]
+struct ValueArgs {
+    /// Key-value pairs for template placeholders (format: key=value)
+    #[arg(short, long)]
+    values: Vec<String>,
+
+    /// TOML, JSON or YAML file with placeholder values (-v takes precedence)
+    #[arg(long = "values-file")]
+    values_files: Vec<PathBuf>,
+
+    /// How to handle a key given more than once with -v
+    #[arg(long, value_enum, default_value_t = DuplicatePolicy::Error)]
+    on_duplicate: DuplicatePolicy,
+
+    /// Process escape sequences such as \n, \t and \u{...} in -v values
+    #[arg(long)]
+    unescape: bool,
+
+    /// Import values from environment variables named <PREFIX><key>
+    #[arg(long, value_name = "PREFIX", num_args = 0..=1, default_missing_value = "TMRS_VAR_")]
+    env_prefix: Option<String>,
+
+    /// Print the merged values and where each came from to stderr
+    #[arg(long)]
+    print_values: bool,
+}
+
+impl ValueArgs {
+    /// Merge values from, in increasing precedence: values files in order,
+    /// environment variables, then -v arguments
+    fn resolve(&self) -> Result<HashMap<String, String>> {
+        let mut merged: HashMap<String, (String, ValueSource)> = HashMap::new();
+        for path in &self.values_files {
+            let values = load_values_file(path)?;
+            drop_replaced_lists(&mut merged, &values);
+            for (key, value) in values {
+                merged.insert(key, (value, ValueSource::File(path.clone())));
+            }
+        }
+        if let Some(prefix) = &self.env_prefix {
+            for (key, value) in load_env_values(prefix) {
+                let var = format!("{}{}", prefix, key);
+                merged.insert(key, (value, ValueSource::Env(var)));
+            }
+        }
+        let values = parse_key_values(&self.values, self.on_duplicate, self.unescape)?;
+        drop_replaced_lists(&mut merged, &values);
+        for (key, value) in values {
+            merged.insert(key, (value, ValueSource::Arg));
+        }
+
+        if self.print_values {
+            let mut keys: Vec<&String> = merged.keys().collect();
+            keys.sort();
+            for key in keys {
+                let (value, source) = &merged[key];
+                eprintln!("{} = {:?} ({})", key, value, source);
             }
-        })
-        .collect()
+        }
+
+        Ok(merged.into_iter().map(|(key, (value, _))| (key, value)).collect())
+    }
 }
 
-/// Apply values to a template
-fn apply_template_values(template: &mut TronTemplate, values: &HashMap<String, String>) -> Result<()> {
-    for (key, value) in values {
-        template.set(key, value)?;
+/// Remove the items of lists that `values` supplies again, so a later source
+/// replaces a whole list instead of overriding it item by item
+fn drop_replaced_lists(merged: &mut HashMap<String, (String, ValueSource)>, values: &HashMap<String, String>) {
+    let lists: Vec<&str> = values.keys().filter_map(|key| list_name(key)).collect();
+    merged.retain(|key, _| !list_name(key).is_some_and(|name| lists.contains(&name)));
+}
+
+/// A tron template that renders `text` unchanged, for handing rendered output
+/// to tron again: every placeholder token in it is set to itself
+fn verbatim_template(text: &str) -> Result<TronTemplate> {
+    let mut template = TronTemplate::new(text)?;
+    for key in placeholder::tron_tokens(text) {
+        template.set(&key, &format!("{}{}{}", placeholder::OPEN, key, placeholder::CLOSE))?;
+    }
+    Ok(template)
+}
+
+/// Render an expanded template and run it with rust-script, adding the
+/// dependencies it declares, then those in `name=version` form
+async fn execute_source(source: &Template, values: &HashMap<String, String>, dependencies: &[String]) -> Result<String> {
+    // The rendered script goes through tron once more on execution, so it must render as it is
+    let rendered = source.render(&source.bind(values)?)?;
+    let mut template_ref = TronRef::new(verbatim_template(&rendered)?);
+    for dep in source.metadata.dependency_specs() {
+        template_ref = template_ref.with_dependency(&dep);
+    }
+    for dep in dependencies {
+        template_ref = template_ref.with_dependency(parse_dependency(dep)?);
+    }
+    template_ref.execute().await
+}
+
+/// Run project hooks in order, printing what they output. The destination
+/// and the value map are passed in environment variables of the hook process.
+async fn run_hooks(hooks: &[Template], values: &HashMap<String, String>, destination: &Path) -> Result<()> {
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let destination = std::env::current_dir()?.join(destination);
+    let env = [
+        (project::DESTINATION_VAR, destination.into_os_string()),
+        (project::VALUES_VAR, project::values_json(values).into()),
+    ];
+    for hook in hooks {
+        let output = execute_hook(hook, values, &env).await.map_err(|e| {
+            let reason = match e {
+                TronError::ExecutionError(reason) => reason,
+                e => e.to_string(),
+            };
+            TronError::ExecutionError(format!("hook '{}' failed: {}", hook.path.display(), reason.trim_end()))
+        })?;
+        print!("{}", output);
+    }
+    Ok(())
+}
+
+/// Render a hook and run it with rust-script the way `execute` does, with
+/// `env` set for the script alone
+async fn execute_hook(hook: &Template, values: &HashMap<String, String>, env: &[(&str, OsString)]) -> Result<String> {
+    let execution_error = |what: &str, e: std::io::Error| TronError::ExecutionError(format!("{}: {}", what, e));
+
+    let mut script = String::new();
+    let dependencies = hook.metadata.dependency_specs();
+    if !dependencies.is_empty() {
+        script.push_str("//! ```cargo\n//! [dependencies]\n");
+        for dep in dependencies {
+            script.push_str(&format!("//! {}\n", dep));
+        }
+        script.push_str("//! ```\n");
+    }
+    script.push_str(&hook.render(&hook.bind(values)?)?);
+
+    let mut file = tempfile::Builder::new().suffix(".rs").tempfile()
+        .map_err(|e| execution_error("Failed to create temp file", e))?;
+    file.write_all(script.as_bytes())
+        .map_err(|e| execution_error("Failed to write temp file", e))?;
+
+    let output = tokio::process::Command::new("rust-script")
+        .arg(file.path())
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .output()
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => TronError::ExecutionError(
+                "rust-script not found. Install with: cargo install rust-script".into()
+            ),
+            _ => execution_error("Failed to execute script", e),
+        })?;
+    if !output.status.success() {
+        return Err(TronError::ExecutionError(String::from_utf8_lossy(&output.stderr).into_owned()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Prompt on a terminal for placeholders that still have no value, then confirm.
+/// Does nothing when prompting is disabled or stdin is not a terminal.
+fn prompt_for_missing(placeholders: &[Placeholder], values: &mut HashMap<String, String>, no_input: bool) -> Result<()> {
+    if no_input || !prompt::is_interactive() {
+        return Ok(());
+    }
+
+    let prompted = prompt::prompt_missing(placeholders, values)?;
+    if !prompted.is_empty() && !prompt::confirm(&prompted, values)? {
+        return Err(TronError::Parse("Aborted".into()));
     }
     Ok(())
 }
 
+/// Pretty-print rendered code when requested, keeping it unchanged with a
+/// warning if it does not parse as Rust
+fn format_output(rendered: String, format: bool) -> String {
+    if !format {
+        return rendered;
+    }
+    match rust::format_source(&rendered) {
+        Ok(formatted) => formatted,
+        Err(err) => {
+            let start = err.span().start();
+            eprintln!(
+                "Warning: output is not valid Rust ({} at {}:{}), writing it unformatted",
+                err, start.line, start.column + 1
+            );
+            rendered
+        }
+    }
+}
+
+/// Map rendered output back to its templates. `separator` is what the renderer
+/// appended after each template. Returns `None` when values themselves contain
+/// placeholder tokens, which makes tron's output differ from the mapped rendering.
+fn build_source_map(
+    rendered: &str,
+    sources: &[Template],
+    bound: &HashMap<String, String>,
+    separator: &str,
+) -> Option<SourceMap> {
+    let mut map = SourceMap::new();
+    let mut mapped = String::new();
+    for source in sources {
+        map.render(source, bound, &mut mapped);
+        map.push_unmapped(&mut mapped, separator);
+    }
+    (mapped == rendered).then_some(map)
+}
+
+/// Syntax-check and write a source map for rendered output, as requested
+fn check_and_map(
+    rendered: &str,
+    sources: &[Template],
+    bound: &HashMap<String, String>,
+    separator: &str,
+    check: bool,
+    source_map: Option<&Path>,
+) -> Result<()> {
+    if !check && source_map.is_none() {
+        return Ok(());
+    }
+
+    let map = build_source_map(rendered, sources, bound, separator);
+    if check {
+        rust::check_source(rendered, map.as_ref())?;
+    }
+    if let Some(path) = source_map {
+        let map = map.ok_or_else(|| TronError::Parse(
+            "Cannot write a source map: a placeholder value contains placeholder syntax".into()
+        ))?;
+        map.save(path)?;
+    }
+    Ok(())
+}
+
+/// Parse a `LINE` or `LINE:COLUMN` position; the column defaults to 1
+fn parse_position(position: &str) -> Result<(usize, usize)> {
+    let invalid = || TronError::Parse(format!(
+        "Invalid position '{}': expected LINE or LINE:COLUMN", position
+    ));
+    let (line, column) = position.split_once(':').unwrap_or((position, "1"));
+    let line: usize = line.parse().map_err(|_| invalid())?;
+    let column: usize = column.parse().map_err(|_| invalid())?;
+    if line == 0 || column == 0 {
+        return Err(invalid());
+    }
+    Ok((line, column))
+}
+
+/// Strict mode is on when requested, or under CI unless explicitly disabled
+fn strict_enabled(strict: bool, no_strict: bool) -> bool {
+    strict || (!no_strict && std::env::var_os("CI").is_some())
+}
+
 async fn run() -> Result<()> {
     let cli = Cli::parse();
 
@@ -103,7 +495,7 @@ async fn run() -> Result<()> {
         Commands::New { output, content, file } => {
             let template_content = match (content, file) {
                 (Some(content), None) => content,
-                (None, Some(file)) => fs::read_to_string(file)?,
+                (None, Some(file)) => stdio::read_input(&file)?,
                 (None, None) => return Err(tron::TronError::Parse(
                     "Either content or file must be provided".into()
                 )),
@@ -112,58 +504,213 @@ async fn run() -> Result<()> {
                 )),
             };
             
-            fs::write(output, template_content)?;
+            stdio::write_output(&output, &template_content)?;
         }
         
-        Commands::Render { template, values, output } => {
-            let mut template = TronTemplate::from_file(template)?;
-            let values = parse_key_values(&values);
-            apply_template_values(&mut template, &values)?;
-            
-            let rendered = template.render()?;
+        Commands::Render {
+            template, includes, values, output, strict, no_strict, format, check, source_map, no_input,
+        } => {
+            let template_file = includes.load(&template)?;
+            let mut values = values.resolve()?;
+            let source = template_file.expand(&values)?;
+            prompt_for_missing(&source.placeholders(), &mut values, no_input)?;
+            if strict_enabled(strict, no_strict) {
+                placeholder::check_coverage(
+                    &source.path, &source.placeholders(), &template_file.names(), &values,
+                )?;
+            }
+            source.validate_values(&values)?;
+
+            let bound = source.bind(&values)?;
+            let rendered = source.render(&bound)?;
+            check_and_map(
+                &rendered, std::slice::from_ref(&source), &bound, "", check, source_map.as_deref(),
+            )?;
+            let rendered = format_output(rendered, format);
             match output {
-                Some(path) => fs::write(path, rendered)?,
+                Some(path) => stdio::write_output(&path, &rendered)?,
                 None => println!("{}", rendered),
             }
         }
         
-        Commands::Execute { template, values, dependencies } => {
-            let template = TronTemplate::from_file(template)?;
-            let mut template_ref = TronRef::new(template);
-            
-            // Add dependencies
-            for dep in dependencies {
-                template_ref = template_ref.with_dependency(&dep);
-            }
+        Commands::Execute { template, includes, values, dependencies, no_input } => {
+            // Resolve blocks against the values before handing the body to tron
+            let mut values = values.resolve()?;
+            let source = includes.load(&template)?.expand(&values)?;
             
-            // Set values
-            let values = parse_key_values(&values);
-            apply_template_values( template_ref.inner_mut(), &values)?;
+            // Set values, asking for any that are missing
+            prompt_for_missing(&source.placeholders(), &mut values, no_input)?;
+            source.validate_values(&values)?;
             
             // Execute and print output
-            let output = template_ref.execute().await?;
+            let output = execute_source(&source, &values, &dependencies).await?;
             println!("{}", output);
         }
         
-        Commands::Assemble { templates, values, output } => {
+        Commands::Assemble { templates, includes, values, output, format, check, source_map } => {
             let mut assembler = TronAssembler::new();
             
-            // Load all templates
+            // Load all templates, binding values and defaults for each
+            let values = values.resolve()?;
+            let mut bound = HashMap::new();
+            let mut sources = Vec::new();
             for path in templates {
-                let template = TronTemplate::from_file(path)?;
-                let tronref = TronRef::new(template);
+                let source = includes.load(&path)?.expand(&values)?;
+                source.validate_values(&values)?;
+                bound.extend(source.bind(&values)?);
+                let tronref = TronRef::new(TronTemplate::new(&source.body)?);
                 assembler.add_template(tronref);
+                sources.push(source);
             }
             
             // Set global values
-            let values = parse_key_values(&values);
-            for (key, value) in values {
-                assembler.set_global(&key, &value)?;
+            let tron_values = TronValues::new(&bound);
+            for (key, value) in &tron_values.values {
+                assembler.set_global(key, value)?;
             }
             
-            // Render and save
-            let combined = assembler.render_all()?;
-            fs::write(output, combined)?;
+            // Render, check and save
+            let combined = sources.iter()
+                .fold(tron_values.restore(&assembler.render_all()?), |combined, source| source.restore(&combined));
+            check_and_map(&combined, &sources, &bound, "\n", check, source_map.as_deref())?;
+            let combined = format_output(combined, format);
+            stdio::write_output(&output, &combined)?;
+        }
+
+        Commands::Generate {
+            template, output, includes, values, force, strict, no_strict, format, no_input,
+        } => {
+            let project = Project::load(&template, &includes.include_dirs)?;
+            let mut values = values.resolve()?;
+            let plan = project.expand(&values)?;
+
+            // Values are checked for every file and hook before any is written
+            let placeholders = plan.placeholders();
+            prompt_for_missing(&placeholders, &mut values, no_input)?;
+            if strict_enabled(strict, no_strict) {
+                placeholder::check_coverage(&template, &placeholders, &project.names(), &values)?;
+            }
+            plan.validate_values(&values)?;
+
+            let mut generated = plan.render(&values)?;
+            for file in &mut generated {
+                if file.path.extension().is_some_and(|extension| extension == "rs") {
+                    file.content = file.content.take().map(|content| format_output(content, format));
+                }
+            }
+            if !force {
+                project::check_overwrite(&generated, &output)?;
+            }
+
+            // Pre-generation hooks can still stop generation; post-generation
+            // hooks see the written files
+            run_hooks(&plan.pre_hooks, &values, &output).await?;
+            project::write(&generated, &output)?;
+            run_hooks(&plan.post_hooks, &values, &output).await?;
+        }
+
+        Commands::Inspect { template, includes, format } => {
+            let source = includes.load(&template)?;
+            let placeholders = source.placeholders();
+            let conditions = source.conditions();
+            let lists = source.lists();
+            let comments = source.comments();
+            let metadata = &source.metadata;
+
+            match format {
+                ReportFormat::Text => {
+                    if let Some(description) = &metadata.description {
+                        println!("{}", description);
+                    }
+                    if let Some(author) = &metadata.author {
+                        println!("Author: {}", author);
+                    }
+                    for dep in metadata.dependency_specs() {
+                        println!("Dependency: {}", dep);
+                    }
+                    if !conditions.is_empty() {
+                        println!("Conditions: {}", conditions.join(", "));
+                    }
+                    if !lists.is_empty() {
+                        println!("Lists: {}", lists.join(", "));
+                    }
+                    let describe = |loc: &placeholder::Location| match &loc.path {
+                        Some(path) => loc.describe(path),
+                        None => format!("{}:{}", loc.line, loc.column),
+                    };
+                    for placeholder in &placeholders {
+                        let locations: Vec<String> = placeholder.locations.iter().map(describe).collect();
+                        let default = match &placeholder.default {
+                            Some(default) => format!(" [default: {:?}]", default),
+                            None => String::new(),
+                        };
+                        println!(
+                            "{} ({}){}: {}",
+                            placeholder.name, placeholder.count, default, locations.join(", ")
+                        );
+                        if let Some(doc) = metadata.placeholders.get(&placeholder.name) {
+                            if let Some(value_type) = &doc.value_type {
+                                println!("    type: {}", value_type);
+                            }
+                            if !doc.choices.is_empty() {
+                                println!("    choices: {}", doc.choices.join(", "));
+                            }
+                            if let Some(pattern) = &doc.pattern {
+                                println!("    pattern: /{}/", pattern);
+                            }
+                            if let Some(description) = &doc.description {
+                                println!("    {}", description);
+                            }
+                        }
+                    }
+                    if !comments.is_empty() {
+                        println!("Comments:");
+                    }
+                    for comment in &comments {
+                        let text: Vec<&str> = comment.text.split_whitespace().collect();
+                        println!("    {}: {}", describe(&comment.location), text.join(" "));
+                    }
+                }
+                ReportFormat::Json => {
+                    let report = serde_json::json!({
+                        "template": template.display().to_string(),
+                        "metadata": metadata,
+                        "placeholders": placeholders,
+                        "conditions": conditions,
+                        "lists": lists,
+                        "comments": comments,
+                    });
+                    let json = serde_json::to_string_pretty(&report)
+                        .map_err(|e| tron::TronError::Parse(e.to_string()))?;
+                    println!("{}", json);
+                }
+            }
+        }
+
+        Commands::Locate { map, position, format } => {
+            let map = SourceMap::load(&map)?;
+            let (line, column) = parse_position(&position)?;
+            let origin = map.lookup(line, column).ok_or_else(|| TronError::Parse(
+                format!("Position {} is not covered by the source map", position)
+            ))?;
+
+            match format {
+                ReportFormat::Text => {
+                    let mut report = format!("{}:{}:{}", origin.path.display(), origin.line, origin.column);
+                    if let Some(name) = origin.placeholder {
+                        report.push_str(&format!(
+                            " (value of placeholder '{}', line {})",
+                            name, origin.value_line.unwrap_or(1)
+                        ));
+                    }
+                    println!("{}", report);
+                }
+                ReportFormat::Json => {
+                    let json = serde_json::to_string_pretty(&origin)
+                        .map_err(|e| TronError::Parse(e.to_string()))?;
+                    println!("{}", json);
+                }
+            }
         }
     }
     
@@ -0,0 +1,284 @@
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tron::{Result, TronError};
+
+use crate::engine::Tag;
+use crate::expr::is_expression;
+use crate::filters;
+use crate::values::list_name;
+
+/// Matches `@[...]@` the same way tron does when extracting placeholders
+fn placeholder_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"@\[([^]]+)\]@").unwrap())
+}
+
+/// Keys tron extracts from `content`, which knows nothing of escapes and raw blocks
+pub fn tron_tokens(content: &str) -> Vec<String> {
+    placeholder_pattern()
+        .captures_iter(content)
+        .map(|capture| capture[1].trim().to_string())
+        .collect()
+}
+
+/// 1-based line and column of a placeholder occurrence
+#[derive(Debug, Clone, Serialize)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+    /// File the occurrence is in, when it is a partial rather than the template itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+impl Location {
+    pub fn new(line: usize, column: usize) -> Self {
+        Self { line, column, path: None }
+    }
+
+    /// `path:line:column`, with `template` as the path unless the location has its own
+    pub fn describe(&self, template: &Path) -> String {
+        let path = self.path.as_deref().unwrap_or(template);
+        format!("{}:{}:{}", path.display(), self.line, self.column)
+    }
+}
+
+/// A single `@[name|filter:default]@` occurrence in template content,
+/// where the filters and default are optional
+#[derive(Debug, Clone)]
+pub struct Occurrence {
+    /// Text between the delimiters, trimmed; this is the key tron sets
+    pub key: String,
+    /// Placeholder name the value is looked up by
+    pub name: String,
+    /// Filters listed after the name, applied to the value in order
+    pub filters: Vec<String>,
+    /// Fallback declared after the first `:`, used when no value is supplied
+    pub default: Option<String>,
+    /// Byte range of the whole `@[...]@` token
+    pub span: Range<usize>,
+    pub location: Location,
+}
+
+impl Occurrence {
+    /// Byte offset in `content` where the key starts, after any padding
+    pub fn key_start(&self, content: &str) -> usize {
+        self.span.start + content[self.span.clone()].find(self.key.as_str()).unwrap_or(0)
+    }
+
+    /// Whether tron substitutes this token. tron trims the key but then looks
+    /// for the untrimmed `@[key]@`, so tokens padded with spaces stay as they are.
+    pub fn is_substituted(&self, content: &str) -> bool {
+        content[self.span.clone()].len() == self.key.len() + 4
+    }
+}
+
+/// Every occurrence of a placeholder name in a template
+#[derive(Debug, Clone, Serialize)]
+pub struct Placeholder {
+    pub name: String,
+    pub count: usize,
+    /// First default declared for this name; it applies to every occurrence
+    pub default: Option<String>,
+    pub locations: Vec<Location>,
+}
+
+/// Split placeholder text into its name, filters and optional default.
+/// The default is everything after the first `:`, so it may itself contain `|`.
+/// An expression is its own name.
+fn parse_key(key: &str) -> (String, Vec<String>, Option<String>) {
+    if is_expression(key) {
+        return (key.to_string(), Vec::new(), None);
+    }
+    let (head, default) = match key.split_once(':') {
+        Some((head, default)) => (head, Some(default.trim().to_string())),
+        None => (key, None),
+    };
+    let mut parts = head.split('|').map(|part| part.trim().to_string());
+    let name = parts.next().unwrap_or_default();
+    (name, parts.collect(), default)
+}
+
+/// Opening delimiter of placeholders and tags
+pub const OPEN: &str = "@[";
+
+/// Closing delimiter of placeholders and tags
+pub const CLOSE: &str = "]@";
+
+/// Whether the delimiter at byte `at` is escaped: an odd number of backslashes
+/// right before it makes it literal, an even number leaves it a delimiter
+pub fn is_escaped(content: &str, at: usize) -> bool {
+    let backslashes = content[..at].bytes().rev().take_while(|&b| b == b'\\').count();
+    backslashes % 2 == 1
+}
+
+/// Matches the `@[endraw]@` tag that ends a raw block
+fn raw_end_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"@\[\s*endraw\s*\]@").unwrap())
+}
+
+/// Find all `@[...]@` tokens, block tags included, in order of appearance.
+/// Escaped tokens and the content of `@[raw]@` blocks are skipped.
+pub fn tokens(content: &str) -> Vec<Occurrence> {
+    let mut line = 1;
+    let mut line_start = 0;
+    let mut cursor = 0;
+    let mut occurrence = |span: Range<usize>, key: &str| {
+        for (i, _) in content[cursor..span.start].match_indices('\n') {
+            line += 1;
+            line_start = cursor + i + 1;
+        }
+        cursor = span.start;
+
+        let key = key.trim().to_string();
+        let (name, filters, default) = parse_key(&key);
+        let location = Location::new(line, content[line_start..span.start].chars().count() + 1);
+        Occurrence { key, name, filters, default, span, location }
+    };
+
+    let mut occurrences = Vec::new();
+    let mut search = 0;
+    while let Some(capture) = placeholder_pattern().captures_at(content, search) {
+        let token = capture.get(0).unwrap();
+        if is_escaped(content, token.start()) {
+            search = token.start() + OPEN.len();
+            continue;
+        }
+        let token = occurrence(token.range(), &capture[1]);
+        search = token.span.end;
+
+        // Nothing up to the closing tag of a raw block is a token
+        let opens_raw = matches!(Tag::parse(&token.key), Some(Ok(Tag::Raw)));
+        occurrences.push(token);
+        if let Some(end) = raw_end_pattern().find_at(content, search).filter(|_| opens_raw) {
+            occurrences.push(occurrence(end.range(), &content[end.start() + 2..end.end() - 2]));
+            search = end.end();
+        }
+    }
+    occurrences
+}
+
+/// Find the tokens tron substitutes, placeholders and expressions, in order of appearance
+pub fn values(content: &str) -> Vec<Occurrence> {
+    tokens(content).into_iter()
+        .filter(|occurrence| !Tag::is_tag(&occurrence.key))
+        .collect()
+}
+
+/// Find all placeholder occurrences in order of appearance
+pub fn scan(content: &str) -> Vec<Occurrence> {
+    values(content).into_iter()
+        .filter(|occurrence| !is_expression(&occurrence.key))
+        .collect()
+}
+
+/// Group placeholder occurrences by name, in order of first appearance
+pub fn collect(content: &str) -> Vec<Placeholder> {
+    group(scan(content))
+}
+
+/// Group occurrences by name, keeping the order of first appearance
+pub fn group(occurrences: Vec<Occurrence>) -> Vec<Placeholder> {
+    let mut placeholders: Vec<Placeholder> = Vec::new();
+    for occurrence in occurrences {
+        match placeholders.iter_mut().find(|p| p.name == occurrence.name) {
+            Some(placeholder) => {
+                placeholder.count += 1;
+                if placeholder.default.is_none() {
+                    placeholder.default = occurrence.default;
+                }
+                placeholder.locations.push(occurrence.location);
+            }
+            None => placeholders.push(Placeholder {
+                name: occurrence.name,
+                count: 1,
+                default: occurrence.default,
+                locations: vec![occurrence.location],
+            }),
+        }
+    }
+    placeholders
+}
+
+/// The value supplied for `name`, treating an empty value as unset
+pub fn supplied<'a>(values: &'a HashMap<String, String>, name: &str) -> Option<&'a String> {
+    values.get(name).filter(|value| !value.is_empty())
+}
+
+/// Placeholders with no value and no default, in order of first appearance
+pub fn missing<'a>(placeholders: &'a [Placeholder], values: &HashMap<String, String>) -> Vec<&'a Placeholder> {
+    placeholders.iter()
+        .filter(|p| p.default.is_none() && supplied(values, &p.name).is_none())
+        .collect()
+}
+
+/// Map each placeholder key in `content` to its supplied value or the default
+/// declared for its name, passed through the occurrence's filters.
+/// Keys with neither are left out, so tron reports them.
+pub fn bind(content: &str, values: &HashMap<String, String>) -> HashMap<String, String> {
+    let defaults = defaults(content);
+
+    scan(content)
+        .into_iter()
+        .filter_map(|occurrence| {
+            let value = supplied(values, &occurrence.name)
+                .or_else(|| defaults.get(&occurrence.name))?;
+            let value = occurrence.filters.iter()
+                .fold(value.clone(), |value, filter| filters::apply(filter, &value));
+            Some((occurrence.key, value))
+        })
+        .collect()
+}
+
+/// Default declared for each placeholder name that has one
+pub fn defaults(content: &str) -> HashMap<String, String> {
+    collect(content)
+        .into_iter()
+        .filter_map(|p| Some((p.name, p.default?)))
+        .collect()
+}
+
+/// Compare a template's placeholders with the supplied values, failing with
+/// every unset placeholder and every key that is not one of the `known` names
+pub fn check_coverage(
+    path: &Path,
+    placeholders: &[Placeholder],
+    known: &[String],
+    values: &HashMap<String, String>,
+) -> Result<()> {
+    let mut problems = Vec::new();
+
+    for placeholder in missing(placeholders, values) {
+        for loc in &placeholder.locations {
+            problems.push(format!(
+                "{}: no value for placeholder '{}'",
+                loc.describe(path), placeholder.name
+            ));
+        }
+    }
+
+    let mut unknown: Vec<&String> = values.keys()
+        .filter(|key| !known.iter().any(|name| name == list_name(key).unwrap_or(key)))
+        .collect();
+    unknown.sort();
+    for key in unknown {
+        problems.push(format!(
+            "{}: supplied key '{}' matches no placeholder",
+            path.display(), key
+        ));
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(TronError::Parse(format!(
+            "Template values do not match placeholders:\n  {}",
+            problems.join("\n  ")
+        )))
+    }
+}
@@ -0,0 +1,374 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tron::{Result, TronError};
+
+use crate::placeholder::Placeholder;
+use crate::template::Template;
+use crate::values::{list_key, list_len, list_name};
+
+/// Extension of the files in a project template that are rendered; other files are copied
+pub const TEMPLATE_EXTENSION: &str = "tmrs";
+
+/// File at the root of a template directory that declares its hooks and
+/// excluded files; it is not generated
+pub const MANIFEST_FILE: &str = "template.toml";
+
+/// Environment variable holding the destination directory, for hooks
+pub const DESTINATION_VAR: &str = "TMRS_DESTINATION";
+
+/// Environment variable holding the value map as a JSON object, for hooks
+pub const VALUES_VAR: &str = "TMRS_VALUES";
+
+/// Contents of the manifest file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    hooks: HookPaths,
+    /// Files and directories, relative to the template directory, that are
+    /// not generated but can still be included or extended
+    #[serde(default)]
+    exclude: Vec<PathBuf>,
+}
+
+/// Hook scripts, relative to the template directory, in the order they run
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HookPaths {
+    /// Run before any file is written
+    #[serde(default)]
+    pre: Vec<PathBuf>,
+    /// Run once every file is written
+    #[serde(default)]
+    post: Vec<PathBuf>,
+}
+
+/// A file of a project template
+struct Entry {
+    /// Path of the file inside the template directory
+    source: PathBuf,
+    /// Each component of the path relative to the template directory, as a
+    /// template for the generated name
+    names: Vec<Template>,
+    /// The file as a template, or `None` when it is copied as it is
+    content: Option<Template>,
+}
+
+/// A template directory whose files generate a tree of files
+pub struct Project {
+    entries: Vec<Entry>,
+    pre_hooks: Vec<Template>,
+    post_hooks: Vec<Template>,
+}
+
+/// A project with every file and hook expanded against the values
+pub struct Plan {
+    files: Vec<PlannedFile>,
+    /// Hook scripts run before and after writing, as templates ready to render
+    pub pre_hooks: Vec<Template>,
+    pub post_hooks: Vec<Template>,
+}
+
+/// A file of a project with its name and content expanded against the values
+struct PlannedFile {
+    source: PathBuf,
+    names: Vec<Template>,
+    content: Option<Template>,
+}
+
+/// A file to write, relative to the destination directory
+pub struct GeneratedFile {
+    pub path: PathBuf,
+    /// Path of the file in the template directory it comes from
+    pub source: PathBuf,
+    /// Rendered content, or `None` for a file copied from `source`
+    pub content: Option<String>,
+}
+
+impl Project {
+    /// Load every file under `root` and the hooks its manifest declares.
+    /// `.tmrs` files and hooks are loaded as templates, with partials looked up in `search_path`.
+    /// Files the manifest excludes and partials or base templates that other
+    /// files pull in are left out.
+    pub fn load(root: &Path, search_path: &[PathBuf]) -> Result<Self> {
+        if !root.is_dir() {
+            return Err(TronError::Parse(format!("'{}' is not a directory", root.display())));
+        }
+
+        let manifest_path = root.join(MANIFEST_FILE);
+        let manifest: Manifest = if manifest_path.is_file() {
+            toml::from_str(&fs::read_to_string(&manifest_path)?).map_err(|e| {
+                TronError::Parse(format!("Invalid manifest '{}': {}", manifest_path.display(), e))
+            })?
+        } else {
+            Manifest::default()
+        };
+        let load_hooks = |paths: &[PathBuf]| paths.iter()
+            .map(|path| {
+                let path = root.join(path);
+                if !path.is_file() {
+                    return Err(TronError::Parse(format!(
+                        "hook '{}' declared in '{}' not found", path.display(), manifest_path.display()
+                    )));
+                }
+                Template::load(&path)?.compose(search_path)
+            })
+            .collect::<Result<Vec<_>>>();
+        let pre_hooks = load_hooks(&manifest.hooks.pre)?;
+        let post_hooks = load_hooks(&manifest.hooks.post)?;
+
+        // The manifest and hooks belong to the template, not to what it generates
+        let skipped: Vec<PathBuf> = std::iter::once(&manifest_path)
+            .chain(pre_hooks.iter().chain(&post_hooks).map(|hook| &hook.path))
+            .filter_map(|path| fs::canonicalize(path).ok())
+            .collect();
+
+        let excluded: Vec<PathBuf> = manifest.exclude.iter().map(|path| root.join(path)).collect();
+
+        let mut loaded = Vec::new();
+        for source in files(root)? {
+            if fs::canonicalize(&source).is_ok_and(|source| skipped.contains(&source))
+                || excluded.iter().any(|path| source.starts_with(path))
+            {
+                continue;
+            }
+            // A partial may not load on its own, so errors wait until it turns out to be generated
+            let content = is_template(&source)
+                .then(|| Template::load(&source).and_then(|template| template.compose(search_path)));
+            loaded.push((source, content));
+        }
+
+        // Partials and base templates are generated through the files that use them
+        let used: Vec<PathBuf> = loaded.iter()
+            .filter_map(|(_, content)| content.as_ref()?.as_ref().ok())
+            .chain(&pre_hooks)
+            .chain(&post_hooks)
+            .flat_map(Template::related)
+            .filter_map(|path| fs::canonicalize(path).ok())
+            .collect();
+
+        let mut entries = Vec::new();
+        for (source, content) in loaded {
+            if fs::canonicalize(&source).is_ok_and(|source| used.contains(&source)) {
+                continue;
+            }
+            let relative = source.strip_prefix(root).unwrap_or(&source);
+            let names = relative.iter()
+                .map(|component| Template::parse(&source, &component.to_string_lossy()))
+                .collect::<Result<Vec<_>>>()?;
+            let content = content.transpose()?;
+            entries.push(Entry { source, names, content });
+        }
+        Ok(Self { entries, pre_hooks, post_hooks })
+    }
+
+    /// Names, contents and hooks as loaded, before blocks are resolved
+    fn templates(&self) -> impl Iterator<Item = &Template> {
+        self.entries.iter()
+            .flat_map(|entry| entry.names.iter().chain(&entry.content))
+            .chain(&self.pre_hooks)
+            .chain(&self.post_hooks)
+    }
+
+    /// Every name a value can be supplied for in any file or hook: placeholders,
+    /// condition variables and lists, in any branch
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for name in self.templates().flat_map(Template::names) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Resolve the blocks of every name, template and hook against the value map
+    pub fn expand(&self, values: &HashMap<String, String>) -> Result<Plan> {
+        let files = self.entries.iter()
+            .map(|entry| Ok(PlannedFile {
+                source: entry.source.clone(),
+                names: entry.names.iter().map(|name| name.expand(values)).collect::<Result<_>>()?,
+                content: entry.content.as_ref().map(|content| content.expand(values)).transpose()?,
+            }))
+            .collect::<Result<_>>()?;
+        let expand_hooks = |hooks: &[Template]| hooks.iter()
+            .map(|hook| hook.expand(values))
+            .collect::<Result<Vec<_>>>();
+        Ok(Plan {
+            files,
+            pre_hooks: expand_hooks(&self.pre_hooks)?,
+            post_hooks: expand_hooks(&self.post_hooks)?,
+        })
+    }
+}
+
+impl PlannedFile {
+    /// Expanded names and content, which take their values from the value map
+    fn templates(&self) -> impl Iterator<Item = &Template> {
+        self.names.iter().chain(&self.content)
+    }
+
+    /// Render the file's path and content. Returns `None` when a component of
+    /// the path renders empty, which leaves the file out.
+    fn render(&self, values: &HashMap<String, String>) -> Result<Option<GeneratedFile>> {
+        let mut path = PathBuf::new();
+        for name in &self.names {
+            let rendered = name.render(&name.bind(values)?)?;
+            if rendered.is_empty() {
+                return Ok(None);
+            }
+            if rendered == "." || rendered == ".." || rendered.contains(['/', '\\']) {
+                return Err(TronError::Parse(format!(
+                    "'{}': name renders as '{}', which is not a single file name",
+                    self.source.display(), rendered
+                )));
+            }
+            path.push(rendered);
+        }
+        if self.content.is_some() {
+            path.set_extension("");
+        }
+
+        let content = match &self.content {
+            Some(content) => Some(content.render(&content.bind(values)?)?),
+            None => None,
+        };
+        Ok(Some(GeneratedFile { path, source: self.source.clone(), content }))
+    }
+}
+
+impl Plan {
+    /// Expanded names, contents and hooks, which take their values from the value map
+    fn templates(&self) -> impl Iterator<Item = &Template> {
+        self.files.iter()
+            .flat_map(PlannedFile::templates)
+            .chain(&self.pre_hooks)
+            .chain(&self.post_hooks)
+    }
+
+    /// Placeholders of every file and hook, merged by name, with each location
+    /// naming its file
+    pub fn placeholders(&self) -> Vec<Placeholder> {
+        let mut merged: Vec<Placeholder> = Vec::new();
+        for template in self.templates() {
+            for mut placeholder in template.placeholders() {
+                for location in &mut placeholder.locations {
+                    location.path.get_or_insert_with(|| template.path.clone());
+                }
+                match merged.iter_mut().find(|p| p.name == placeholder.name) {
+                    Some(existing) => {
+                        existing.count += placeholder.count;
+                        existing.default = existing.default.take().or(placeholder.default);
+                        existing.locations.extend(placeholder.locations);
+                    }
+                    None => merged.push(placeholder),
+                }
+            }
+        }
+        merged
+    }
+
+    /// Check the values against the declared placeholder types of every file and hook
+    pub fn validate_values(&self, values: &HashMap<String, String>) -> Result<()> {
+        for template in self.templates() {
+            template.validate_values(values)?;
+        }
+        Ok(())
+    }
+
+    /// Render the paths and contents of the files, leaving out those whose
+    /// name renders empty. Fails when two files end up at the same path.
+    pub fn render(&self, values: &HashMap<String, String>) -> Result<Vec<GeneratedFile>> {
+        let mut generated: Vec<GeneratedFile> = Vec::new();
+        for file in &self.files {
+            let Some(file) = file.render(values)? else {
+                continue;
+            };
+            if let Some(other) = generated.iter().find(|other| other.path == file.path) {
+                return Err(TronError::Parse(format!(
+                    "'{}' and '{}' both generate '{}'",
+                    other.source.display(), file.source.display(), file.path.display()
+                )));
+            }
+            generated.push(file);
+        }
+        Ok(generated)
+    }
+}
+
+/// Fail, listing them all, if any generated file already exists under `destination`
+pub fn check_overwrite(files: &[GeneratedFile], destination: &Path) -> Result<()> {
+    let existing: Vec<String> = files.iter()
+        .map(|file| destination.join(&file.path))
+        .filter(|path| path.exists())
+        .map(|path| path.display().to_string())
+        .collect();
+    if existing.is_empty() {
+        Ok(())
+    } else {
+        Err(TronError::Parse(format!(
+            "Refusing to overwrite existing files (use --force):\n  {}",
+            existing.join("\n  ")
+        )))
+    }
+}
+
+/// Write generated files under `destination`, creating directories as needed
+pub fn write(files: &[GeneratedFile], destination: &Path) -> Result<()> {
+    for file in files {
+        let path = destination.join(&file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match &file.content {
+            Some(content) => fs::write(&path, content)?,
+            None => {
+                fs::copy(&file.source, &path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The value map as a JSON object, with each list as an array of its items
+pub fn values_json(values: &HashMap<String, String>) -> String {
+    let mut object = BTreeMap::new();
+    for (key, value) in values {
+        match list_name(key) {
+            Some(name) => {
+                let items: Vec<&String> = (0..list_len(values, name))
+                    .filter_map(|i| values.get(&list_key(name, i)))
+                    .collect();
+                object.insert(name.to_string(), serde_json::json!(items));
+            }
+            None => {
+                object.insert(key.clone(), serde_json::json!(value));
+            }
+        }
+    }
+    serde_json::Value::from_iter(object).to_string()
+}
+
+fn is_template(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == TEMPLATE_EXTENSION)
+}
+
+/// Files under `dir`, recursively, in a stable order
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<_>>()?;
+    entries.sort();
+
+    let mut files = Vec::new();
+    for path in entries {
+        if path.is_dir() {
+            files.extend(self::files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, IsTerminal, Write};
+use tron::{Result, TronError};
+
+use crate::placeholder::{self, Placeholder};
+
+/// Delimiter that starts and ends a multi-line value
+const MULTILINE_DELIMITER: &str = "\"\"\"";
+
+/// Prompts are only shown when both stdin and stderr are attached to a terminal
+pub fn is_interactive() -> bool {
+    io::stdin().is_terminal() && io::stderr().is_terminal()
+}
+
+/// Ask for every placeholder that has no value or default yet.
+/// Returns the names that were prompted for, in template order.
+pub fn prompt_missing(
+    placeholders: &[Placeholder],
+    values: &mut HashMap<String, String>,
+) -> Result<Vec<String>> {
+    let missing: Vec<String> = placeholder::missing(placeholders, values)
+        .into_iter()
+        .map(|p| p.name.clone())
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(missing);
+    }
+
+    eprintln!(
+        "{} placeholder(s) have no value. Enter {} on its own line to type several lines, \
+         and again to finish.",
+        missing.len(),
+        MULTILINE_DELIMITER
+    );
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    for name in &missing {
+        let value = loop {
+            eprint!("{}: ", name);
+            io::stderr().flush()?;
+
+            let line = next_line(&mut lines)?;
+            let value = if line.trim() == MULTILINE_DELIMITER {
+                read_multiline(&mut lines)?
+            } else {
+                line
+            };
+
+            if !value.is_empty() {
+                break value;
+            }
+            eprintln!("A value is required for '{}'", name);
+        };
+        values.insert(name.clone(), value);
+    }
+
+    Ok(missing)
+}
+
+/// Show the values that were entered and ask whether to continue
+pub fn confirm(prompted: &[String], values: &HashMap<String, String>) -> Result<bool> {
+    eprintln!("\nValues entered:");
+    for name in prompted {
+        let value = &values[name];
+        let line_count = value.lines().count();
+        if line_count > 1 {
+            eprintln!("  {} = {} ... ({} lines)", name, value.lines().next().unwrap_or(""), line_count);
+        } else {
+            eprintln!("  {} = {}", name, value);
+        }
+    }
+
+    eprint!("Continue? [Y/n] ");
+    io::stderr().flush()?;
+    let stdin = io::stdin();
+    let answer = next_line(&mut stdin.lock().lines())?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "" | "y" | "yes"))
+}
+
+/// Read lines until the closing delimiter, joined with newlines
+fn read_multiline(lines: &mut impl Iterator<Item = io::Result<String>>) -> Result<String> {
+    let mut collected = Vec::new();
+    loop {
+        eprint!("... ");
+        io::stderr().flush()?;
+        let line = next_line(lines)?;
+        if line.trim() == MULTILINE_DELIMITER {
+            return Ok(collected.join("\n"));
+        }
+        collected.push(line);
+    }
+}
+
+fn next_line(lines: &mut impl Iterator<Item = io::Result<String>>) -> Result<String> {
+    match lines.next() {
+        Some(line) => Ok(line?),
+        None => Err(TronError::Parse("Input ended while prompting for values".into())),
+    }
+}
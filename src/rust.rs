@@ -0,0 +1,42 @@
+use tron::{Result, TronError};
+
+use crate::sourcemap::{self, SourceMap};
+
+/// Pretty-print rendered Rust source, or give back the parse error if it is not valid Rust.
+/// Like rustfmt-less formatters in general, this drops non-doc comments.
+pub fn format_source(source: &str) -> std::result::Result<String, syn::Error> {
+    let file = syn::parse_file(source)?;
+    Ok(prettyplease::unparse(&file))
+}
+
+/// Parse rendered output as a Rust file, describing the syntax error and, when
+/// a source map is available, the template text or placeholder value behind it
+pub fn check_source(rendered: &str, map: Option<&SourceMap>) -> Result<()> {
+    let Err(err) = syn::parse_file(rendered) else {
+        return Ok(());
+    };
+
+    let start = err.span().start();
+    let mut message = format!(
+        "Rendered output is not valid Rust: {} (output line {}, column {})",
+        err, start.line, start.column + 1
+    );
+    if let Some(position) = map.and_then(|map| map.lookup(start.line, start.column + 1)) {
+        let location = format!("{}:{}:{}", position.path.display(), position.line, position.column);
+        match position.placeholder {
+            Some(name) => message.push_str(&format!(
+                "\n  caused by the value of placeholder '{}' (line {} of the value) at {}",
+                name, position.value_line.unwrap_or(1), location
+            )),
+            None => message.push_str(&format!("\n  in template text at {}", location)),
+        }
+
+        // Text that only follows a value after whitespace is usually broken by that value
+        let offset = sourcemap::offset_of(rendered, start.line, start.column);
+        let leading = rendered.get(position.segment_start..offset).unwrap_or_default();
+        if let Some(name) = position.preceding_placeholder.filter(|_| leading.trim().is_empty()) {
+            message.push_str(&format!("\n  right after the value of placeholder '{}'", name));
+        }
+    }
+    Err(TronError::Parse(message))
+}
@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use tron::{Result, TronError};
+
+use crate::placeholder;
+use crate::stdio;
+use crate::template::Template;
+
+/// Version written to source map files, bumped on incompatible changes
+const SOURCE_MAP_VERSION: u32 = 1;
+
+/// A run of rendered output copied from template text or from a placeholder value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Segment {
+    /// Byte offset in the rendered output where the run starts
+    pub start: usize,
+    /// Byte offset in the rendered output just past the run
+    pub end: usize,
+    /// 1-based line and column in the rendered output where the run starts
+    pub line: usize,
+    pub column: usize,
+    /// 1-based line and column in the rendered output just past the run
+    pub end_line: usize,
+    pub end_column: usize,
+    /// Index into the map's `sources`
+    pub source: usize,
+    /// Template position the run starts at: the template text itself, or the
+    /// placeholder token for a value
+    pub template_line: usize,
+    pub template_column: usize,
+    /// Placeholder whose value this run is; absent for template text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<String>,
+}
+
+/// Template position that produced a location in rendered output
+#[derive(Debug, Serialize)]
+pub struct Position<'a> {
+    pub path: &'a Path,
+    pub line: usize,
+    pub column: usize,
+    /// Placeholder whose value produced the location, if it was not template text
+    pub placeholder: Option<&'a str>,
+    /// 1-based line within that placeholder's value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_line: Option<usize>,
+    /// Placeholder whose value comes right before this run of template text
+    #[serde(skip)]
+    pub preceding_placeholder: Option<&'a str>,
+    /// Byte offset in the output where the containing run starts
+    #[serde(skip)]
+    pub segment_start: usize,
+}
+
+/// Maps rendered output back to template text and placeholder values.
+/// Serialized as JSON for `--source-map` and read back by `locate`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SourceMap {
+    pub version: u32,
+    pub sources: Vec<PathBuf>,
+    pub segments: Vec<Segment>,
+    /// Number of characters on each line of the output, newline excluded
+    pub line_widths: Vec<usize>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self { version: SOURCE_MAP_VERSION, line_widths: vec![0], ..Self::default() }
+    }
+
+    /// Render `template` with `bound` values the way tron substitutes them,
+    /// appending to `output` and recording where each run came from
+    pub fn render(&mut self, template: &Template, bound: &HashMap<String, String>, output: &mut String) {
+        let body = &template.body;
+        let mut cursor = 0;
+
+        for occurrence in placeholder::values(body) {
+            if !occurrence.is_substituted(body) {
+                continue;
+            }
+            let Some(value) = bound.get(&occurrence.key) else {
+                continue;
+            };
+
+            self.push_text(output, template, cursor..occurrence.span.start);
+            let (path, location) = template.locate(occurrence.span.start);
+            let source = self.source_index(path);
+            self.push(output, source, value, (location.line, location.column), Some(occurrence.name));
+            cursor = occurrence.span.end;
+        }
+        self.push_text(output, template, cursor..body.len());
+    }
+
+    /// Index of `path` in `sources`, adding it if it is not there yet
+    fn source_index(&mut self, path: &Path) -> usize {
+        match self.sources.iter().position(|source| source == path) {
+            Some(index) => index,
+            None => {
+                self.sources.push(path.to_path_buf());
+                self.sources.len() - 1
+            }
+        }
+    }
+
+    /// Append text that `render` did not produce from a template, such as separators
+    pub fn push_unmapped(&mut self, output: &mut String, text: &str) {
+        output.push_str(text);
+        self.advance(text);
+    }
+
+    /// Line and column just past the output rendered so far
+    fn end(&self) -> (usize, usize) {
+        (self.line_widths.len(), self.line_widths.last().map_or(0, |width| width + 1))
+    }
+
+    /// Move the end position past `text`
+    fn advance(&mut self, text: &str) {
+        let mut lines = text.split('\n');
+        if let (Some(first), Some(width)) = (lines.next(), self.line_widths.last_mut()) {
+            *width += first.chars().count();
+        }
+        self.line_widths.extend(lines.map(|line| line.chars().count()));
+    }
+
+    /// Append a range of template text, with the literal text its markers stand for
+    fn push_text(&mut self, output: &mut String, template: &Template, range: Range<usize>) {
+        let mut cursor = range.start;
+        for (marker, literal) in template.literals_in(range.clone()) {
+            self.push_runs(output, template, cursor..marker.start);
+            let (path, location) = template.locate(marker.start);
+            let source = self.source_index(path);
+            self.push(output, source, literal, (location.line, location.column), None);
+            cursor = marker.end;
+        }
+        self.push_runs(output, template, cursor..range.end);
+    }
+
+    /// Append a range of template text, one segment for each run of the body
+    fn push_runs(&mut self, output: &mut String, template: &Template, range: Range<usize>) {
+        for run in template.runs(range) {
+            let (path, location) = template.locate(run.start);
+            let source = self.source_index(path);
+            self.push(output, source, &template.body[run], (location.line, location.column), None);
+        }
+    }
+
+    fn push(
+        &mut self,
+        output: &mut String,
+        source: usize,
+        text: &str,
+        (template_line, template_column): (usize, usize),
+        placeholder: Option<String>,
+    ) {
+        if text.is_empty() {
+            return;
+        }
+        let (line, column) = self.end();
+        let start = output.len();
+        output.push_str(text);
+        self.advance(text);
+        let (end_line, end_column) = self.end();
+        self.segments.push(Segment {
+            start,
+            end: output.len(),
+            line,
+            column,
+            end_line,
+            end_column,
+            source,
+            template_line,
+            template_column,
+            placeholder,
+        });
+    }
+
+    /// Find the template position behind a 1-based line and column of the output.
+    /// Returns `None` past the end of a line or of the output, and for text
+    /// no template produced, such as separators.
+    pub fn lookup(&self, line: usize, column: usize) -> Option<Position<'_>> {
+        // The newline ending a line is a position too
+        let width = self.line_widths.get(line.checked_sub(1)?)?;
+        if column == 0 || column > width + usize::from(line < self.line_widths.len()) {
+            return None;
+        }
+        let index = self.segments
+            .partition_point(|s| (s.line, s.column) <= (line, column))
+            .checked_sub(1)?;
+        let segment = &self.segments[index];
+        if (line, column) >= (segment.end_line, segment.end_column) {
+            return None;
+        }
+        let path = self.sources.get(segment.source)?;
+        let preceding_placeholder = index.checked_sub(1)
+            .and_then(|i| self.segments[i].placeholder.as_deref())
+            .filter(|_| segment.placeholder.is_none() && self.segments[index - 1].end == segment.start);
+
+        let mut position = Position {
+            path,
+            line: segment.template_line,
+            column: segment.template_column,
+            placeholder: segment.placeholder.as_deref(),
+            value_line: None,
+            preceding_placeholder,
+            segment_start: segment.start,
+        };
+        if segment.placeholder.is_some() {
+            position.value_line = Some(line - segment.line + 1);
+        } else if line == segment.line {
+            // Template text is copied verbatim, so offsets carry over
+            position.column += column - segment.column;
+        } else {
+            position.line += line - segment.line;
+            position.column = column;
+        }
+        Some(position)
+    }
+
+    /// Write the map as JSON
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| TronError::Parse(e.to_string()))?;
+        stdio::write_output(path, &json)
+    }
+
+    /// Read a map written by `save`
+    pub fn load(path: &Path) -> Result<Self> {
+        let map: Self = serde_json::from_str(&stdio::read_input(path)?).map_err(|e| {
+            TronError::Parse(format!("Invalid source map '{}': {}", path.display(), e))
+        })?;
+        if map.version != SOURCE_MAP_VERSION {
+            return Err(TronError::Parse(format!(
+                "Source map '{}' has unsupported version {}",
+                path.display(), map.version
+            )));
+        }
+        Ok(map)
+    }
+}
+
+/// Byte offset of a 1-based line and 0-based character column
+pub fn offset_of(text: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = text.split_inclusive('\n').take(line - 1).map(str::len).sum();
+    let rest = &text[line_start.min(text.len())..];
+    line_start + rest.char_indices().nth(column).map_or(rest.len(), |(i, _)| i)
+}
@@ -0,0 +1,51 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tron::{Result, TronError};
+
+/// Path argument that stands for stdin or stdout
+pub const STDIO_PATH: &str = "-";
+
+/// Name shown in messages for content read from stdin
+pub const STDIN_NAME: &str = "<stdin>";
+
+static STDIN_READ: AtomicBool = AtomicBool::new(false);
+
+/// Whether a path argument means stdin or stdout
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO_PATH
+}
+
+/// Read all of stdin. Only one input per invocation may come from stdin.
+pub fn read_stdin() -> Result<String> {
+    if STDIN_READ.swap(true, Ordering::SeqCst) {
+        return Err(TronError::Parse(
+            "stdin can only be used for one input per invocation".into()
+        ));
+    }
+    let mut content = String::new();
+    io::stdin().read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Read a file, or stdin when the path is `-`
+pub fn read_input(path: &Path) -> Result<String> {
+    if is_stdio(path) {
+        read_stdin()
+    } else {
+        Ok(fs::read_to_string(path)?)
+    }
+}
+
+/// Write a file, or stdout when the path is `-`
+pub fn write_output(path: &Path, content: &str) -> Result<()> {
+    if is_stdio(path) {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(content.as_bytes())?;
+        stdout.flush()?;
+    } else {
+        fs::write(path, content)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,756 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use tron::{Result, TronError, TronTemplate};
+
+use crate::engine::{self, Expansion, Tag};
+use crate::expr::{self, Expr, Lookup};
+use crate::filters;
+use crate::placeholder::{self, Location, Placeholder, CLOSE, OPEN};
+use crate::stdio;
+use crate::types::{self, ValueType};
+use crate::values::list_name;
+
+/// Line that opens and closes a TOML front-matter block
+const FRONT_MATTER_FENCE: &str = "+++";
+
+/// Template metadata declared in the front-matter block
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Metadata {
+    pub description: Option<String>,
+    pub author: Option<String>,
+    /// Cargo dependencies added when the template is executed
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, toml::Value>,
+    /// Documentation for individual placeholders, keyed by name
+    #[serde(default)]
+    pub placeholders: BTreeMap<String, PlaceholderDoc>,
+    /// Opening and closing delimiters this file uses instead of `@[` and `]@`
+    pub delimiters: Option<[String; 2]>,
+}
+
+/// Documentation and validation rules for a single placeholder
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlaceholderDoc {
+    pub description: Option<String>,
+    /// Kind of value accepted; values are validated against it before rendering
+    #[serde(rename = "type")]
+    pub value_type: Option<ValueType>,
+    /// Allowed values for `type = "enum"`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<String>,
+    /// Regular expression for `type = "regex"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+impl PlaceholderDoc {
+    /// Check that `choices` and `pattern` are given exactly when the type needs them
+    fn check_declaration(&self) -> std::result::Result<(), String> {
+        match self.value_type {
+            Some(ValueType::Enum) if self.choices.is_empty() => {
+                return Err("type \"enum\" needs a non-empty `choices` list".into());
+            }
+            Some(ValueType::Regex) => match &self.pattern {
+                Some(pattern) => {
+                    types::compile_pattern(pattern)?;
+                }
+                None => return Err("type \"regex\" needs a `pattern`".into()),
+            },
+            _ => {}
+        }
+        if !self.choices.is_empty() && self.value_type != Some(ValueType::Enum) {
+            return Err("`choices` is only allowed with type \"enum\"".into());
+        }
+        if self.pattern.is_some() && self.value_type != Some(ValueType::Regex) {
+            return Err("`pattern` is only allowed with type \"regex\"".into());
+        }
+        Ok(())
+    }
+
+    /// Check a value against the declared type, if any
+    pub fn check(&self, value: &str) -> std::result::Result<(), String> {
+        match self.value_type {
+            Some(value_type) => value_type.check(value, &self.choices, self.pattern.as_deref()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Metadata {
+    /// Dependencies in the `name = spec` form passed to rust-script
+    pub fn dependency_specs(&self) -> Vec<String> {
+        self.dependencies.iter()
+            .map(|(name, spec)| format!("{} = {}", name, spec))
+            .collect()
+    }
+}
+
+/// A run of a template body copied from a file
+#[derive(Debug, Clone)]
+struct Chunk {
+    /// Byte offset in the body where the run starts
+    start: usize,
+    path: PathBuf,
+    /// Position of the run's first character in that file
+    location: Location,
+}
+
+/// A `@[# ...]@` comment and where it is written
+#[derive(Debug, Serialize)]
+pub struct Comment {
+    pub text: String,
+    pub location: Location,
+}
+
+/// A `.tmrs` file split into its optional front-matter and its body
+#[derive(Debug)]
+pub struct Template {
+    pub path: PathBuf,
+    pub metadata: Metadata,
+    /// Template text handed to tron, without the front-matter
+    pub body: String,
+    /// Where each run of the body came from, in order
+    chunks: Vec<Chunk>,
+    /// Markers left in the body by `expand` and the literal text each stands for
+    literals: Vec<(String, String)>,
+    /// Partials and base templates `compose` pulled in, nested ones included
+    related: Vec<PathBuf>,
+}
+
+/// Bound values as tron takes them. tron treats an empty value as unset, so
+/// each empty value is set to a marker that `restore` removes after rendering.
+pub struct TronValues {
+    pub values: HashMap<String, String>,
+    empty: Vec<String>,
+}
+
+impl TronValues {
+    pub fn new(bound: &HashMap<String, String>) -> Self {
+        let mut empty = Vec::new();
+        let values = bound.iter()
+            .map(|(key, value)| {
+                if !value.is_empty() {
+                    return (key.clone(), value.clone());
+                }
+                let marker = engine::literal_marker();
+                empty.push(marker.clone());
+                (key.clone(), marker)
+            })
+            .collect();
+        Self { values, empty }
+    }
+
+    /// Remove the markers of empty values from rendered output
+    pub fn restore(&self, rendered: &str) -> String {
+        self.empty.iter().fold(rendered.to_string(), |text, marker| text.replace(marker.as_str(), ""))
+    }
+}
+
+impl Template {
+    /// Load a template file, or stdin when the path is `-`, parsing its front-matter if present
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = stdio::read_input(path)?;
+        if stdio::is_stdio(path) {
+            Self::parse(Path::new(stdio::STDIN_NAME), &content)
+        } else {
+            Self::parse(path, &content)
+        }
+    }
+
+    /// Split `content` into front-matter and body.
+    ///
+    /// Front-matter is a TOML block between two `+++` lines at the very top:
+    ///
+    /// ```text
+    /// +++
+    /// description = "A struct with a constructor"
+    /// [dependencies]
+    /// serde = { version = "1.0", features = ["derive"] }
+    /// +++
+    /// struct @[name]@ { ... }
+    /// ```
+    pub fn parse(path: &Path, content: &str) -> Result<Self> {
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        let mut lines = content.split_inclusive('\n');
+
+        let opens_block = lines.next().is_some_and(|line| line.trim_end() == FRONT_MATTER_FENCE);
+        if !opens_block {
+            return Self::from_file(path, Metadata::default(), content.to_string(), 1).checked();
+        }
+
+        let mut header = String::new();
+        let mut header_lines = 1;
+        for line in lines.by_ref() {
+            header_lines += 1;
+            if line.trim_end() == FRONT_MATTER_FENCE {
+                let metadata: Metadata = toml::from_str(&header).map_err(|e| {
+                    TronError::Parse(format!("Invalid front-matter in '{}': {}", path.display(), e))
+                })?;
+                if metadata.delimiters.iter().flatten().any(String::is_empty) {
+                    return Err(TronError::Parse(format!(
+                        "Invalid front-matter in '{}': delimiters cannot be empty", path.display()
+                    )));
+                }
+                for (name, doc) in &metadata.placeholders {
+                    doc.check_declaration().map_err(|e| TronError::Parse(format!(
+                        "Invalid declaration for placeholder '{}' in '{}': {}",
+                        name, path.display(), e
+                    )))?;
+                }
+                return Self::from_file(path, metadata, lines.collect(), header_lines + 1)
+                    .with_delimiters()?
+                    .checked();
+            }
+            header.push_str(line);
+        }
+
+        Err(TronError::Parse(format!(
+            "Front-matter in '{}' is not closed with a '{}' line",
+            path.display(),
+            FRONT_MATTER_FENCE
+        )))
+    }
+
+    /// A template whose body starts at `first_line` of the file at `path`
+    fn from_file(path: &Path, metadata: Metadata, body: String, first_line: usize) -> Self {
+        let chunk = Chunk {
+            start: 0,
+            path: path.to_path_buf(),
+            location: Location::new(first_line, 1),
+        };
+        Self {
+            path: path.to_path_buf(),
+            metadata,
+            body,
+            chunks: vec![chunk],
+            literals: Vec::new(),
+            related: Vec::new(),
+        }
+    }
+
+    /// An empty body to build on, with the path and metadata of this template
+    fn empty(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            metadata: self.metadata.clone(),
+            body: String::new(),
+            chunks: Vec::new(),
+            literals: Vec::new(),
+            related: self.related.clone(),
+        }
+    }
+
+    /// Rewrite a body written with the delimiters declared in the front-matter
+    /// into `@[ ]@` syntax. `@[` in the text is kept literal, an odd number of
+    /// `\` before the opening delimiter makes that delimiter literal, and the
+    /// content of raw blocks is kept as it is.
+    fn with_delimiters(self) -> Result<Self> {
+        let Some([open, close]) = self.metadata.delimiters.clone() else {
+            return Ok(self);
+        };
+        let body = &self.body;
+        let mut translated = self.empty();
+        // Delimited tokens as (byte range, trimmed text between the delimiters)
+        let token_at = |start: usize| {
+            let inner = start + open.len();
+            let end = inner + body[inner..].find(close.as_str())?;
+            Some((start..end + close.len(), body[inner..end].trim()))
+        };
+
+        let mut cursor = 0;
+        let mut search = 0;
+        while let Some(start) = body[search..].find(open.as_str()).map(|i| search + i) {
+            search = start + open.len();
+            if placeholder::is_escaped(body, start) {
+                translated.append_text(&self, cursor..start - 1);
+                cursor = start;
+                continue;
+            }
+            let Some((span, key)) = token_at(start) else {
+                break;
+            };
+            if key.is_empty() {
+                continue;
+            }
+            if key.contains(']') {
+                return Err(self.error_at(start, &format!("']' is not allowed in '{}{}{}'", open, key, close)));
+            }
+            translated.append_text(&self, cursor..start);
+            translated.insert(&self, start, &format!("{}{}{}", OPEN, key, CLOSE));
+            cursor = span.end;
+            search = span.end;
+
+            if key == Tag::Raw.keyword() {
+                let end = body[cursor..].match_indices(open.as_str())
+                    .find_map(|(i, _)| token_at(cursor + i).filter(|(_, key)| *key == Tag::EndRaw.keyword()));
+                let Some((end, _)) = end else {
+                    break;
+                };
+                translated.append(&self, cursor..end.start);
+                translated.insert(&self, end.start, &format!("{}{}{}", OPEN, Tag::EndRaw.keyword(), CLOSE));
+                cursor = end.end;
+                search = end.end;
+            }
+        }
+        translated.append_text(&self, cursor..body.len());
+        Ok(translated)
+    }
+
+    /// Reject placeholders that use unknown filters
+    fn checked(self) -> Result<Self> {
+        for occurrence in placeholder::scan(&self.body) {
+            if let Some(filter) = occurrence.filters.iter().find(|f| !filters::is_known(f)) {
+                return Err(self.error_at(
+                    occurrence.span.start,
+                    &format!("unknown filter '{}' in '@[{}]@'", filter, occurrence.key),
+                ));
+            }
+        }
+        Ok(self)
+    }
+
+    /// An error message prefixed with the file position of a body offset
+    fn error_at(&self, offset: usize, message: &str) -> TronError {
+        let (path, location) = self.locate(offset);
+        TronError::Parse(format!("{}:{}:{}: {}", path.display(), location.line, location.column, message))
+    }
+
+    /// File and position a byte offset of the body was copied from
+    pub fn locate(&self, offset: usize) -> (&Path, Location) {
+        let index = self.chunks.partition_point(|c| c.start <= offset).saturating_sub(1);
+        let Some(chunk) = self.chunks.get(index) else {
+            return (&self.path, Location::new(1, 1));
+        };
+        let text = &self.body[chunk.start..offset];
+        let location = match text.rfind('\n') {
+            Some(i) => Location::new(
+                chunk.location.line + text.matches('\n').count(),
+                text[i + 1..].chars().count() + 1,
+            ),
+            None => Location::new(
+                chunk.location.line,
+                chunk.location.column + text.chars().count(),
+            ),
+        };
+        (&chunk.path, location)
+    }
+
+    /// Split a byte range of the body where it crosses from one run to the next
+    pub fn runs(&self, range: Range<usize>) -> Vec<Range<usize>> {
+        let mut runs = Vec::new();
+        let mut start = range.start;
+        for chunk in &self.chunks {
+            if chunk.start > start && chunk.start < range.end {
+                runs.push(start..chunk.start);
+                start = chunk.start;
+            }
+        }
+        runs.push(start..range.end);
+        runs
+    }
+
+    /// Files `compose` pulled in with `@[include]@` and `@[extends]@`, as found
+    pub fn related(&self) -> &[PathBuf] {
+        &self.related
+    }
+
+    /// Splice in the partials named by `@[include "path"]@` and apply
+    /// `@[extends "path"]@`, then check the block structure of the result.
+    /// Other files are looked up relative to the file that names them, then in
+    /// each directory of `search_path`.
+    pub fn compose(&self, search_path: &[PathBuf]) -> Result<Self> {
+        let composed = self.resolve(search_path, &mut Vec::new())?;
+        let syntax_error = |e: engine::SyntaxError| composed.error_at(e.offset, &e.message);
+        engine::parse(&composed.body).map_err(syntax_error)?;
+        engine::blocks(&composed.body).map_err(syntax_error)?;
+        Ok(composed)
+    }
+
+    /// `stack` holds the files being resolved, outermost first, to detect cycles
+    fn resolve(&self, search_path: &[PathBuf], stack: &mut Vec<PathBuf>) -> Result<Self> {
+        stack.push(self.path.clone());
+        let mut resolved = self.include_partials(search_path, stack)?;
+        let extends = engine::extends(&resolved.body)
+            .map_err(|e| resolved.error_at(e.offset, &e.message))?;
+        if let Some(extends) = extends {
+            let base = resolved.load_related(&extends, "base template", search_path, stack)?;
+            let mut composed = base.overridden_by(&resolved, extends.span)?;
+            composed.related.push(base.path.clone());
+            composed.related.extend(base.related);
+            resolved = composed;
+        }
+        stack.pop();
+        Ok(resolved)
+    }
+
+    fn include_partials(&self, search_path: &[PathBuf], stack: &mut Vec<PathBuf>) -> Result<Self> {
+        let mut composed = self.empty();
+
+        let mut cursor = 0;
+        for include in engine::includes(&self.body) {
+            composed.append(self, cursor..include.span.start);
+            cursor = include.span.end;
+
+            let partial = self.load_related(&include, "partial", search_path, stack)?;
+            composed.related.push(partial.path.clone());
+            composed.related.extend(partial.related.iter().cloned());
+            composed.merge_metadata(&partial.metadata);
+            composed.append(&partial, 0..partial.body.len());
+        }
+        composed.append(self, cursor..self.body.len());
+        Ok(composed)
+    }
+
+    /// Find, load and resolve the file an `@[include]@` or `@[extends]@` tag names
+    fn load_related(
+        &self,
+        tag: &engine::FileTag,
+        kind: &str,
+        search_path: &[PathBuf],
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<Self> {
+        let path = self.find_file(&tag.path, search_path)
+            .ok_or_else(|| self.error_at(tag.offset, &format!(
+                "{} '{}' not found next to '{}'{}",
+                kind,
+                tag.path,
+                self.path.display(),
+                if search_path.is_empty() { String::new() } else { " or in the include directories".into() },
+            )))?;
+        if stack.iter().any(|outer| same_file(outer, &path)) {
+            let chain: Vec<String> = stack.iter()
+                .chain([&path])
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(self.error_at(tag.offset, &format!("template cycle: {}", chain.join(" -> "))));
+        }
+        Template::load(&path)?.resolve(search_path, stack)
+    }
+
+    /// First existing file for a path named in the body
+    fn find_file(&self, name: &str, search_path: &[PathBuf]) -> Option<PathBuf> {
+        let including_dir = self.path.parent().unwrap_or(Path::new(""));
+        std::iter::once(including_dir)
+            .chain(search_path.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// Add the dependencies and placeholder declarations of another file that
+    /// this one does not declare itself
+    fn merge_metadata(&mut self, other: &Metadata) {
+        for (name, spec) in &other.dependencies {
+            self.metadata.dependencies.entry(name.clone()).or_insert_with(|| spec.clone());
+        }
+        for (name, doc) in &other.placeholders {
+            self.metadata.placeholders.entry(name.clone()).or_insert_with(|| doc.clone());
+        }
+    }
+
+    /// This base template with its blocks replaced by those of `child`, whose
+    /// `@[extends]@` tag takes up `extends`. Block tags are kept so the result
+    /// can be extended in turn.
+    fn overridden_by(&self, child: &Template, extends: Range<usize>) -> Result<Self> {
+        let child_error = |e: engine::SyntaxError| child.error_at(e.offset, &e.message);
+        let overrides = engine::blocks(&child.body).map_err(child_error)?;
+        let blocks = engine::blocks(&self.body).map_err(|e| self.error_at(e.offset, &e.message))?;
+
+        // Everything in a child goes in blocks that replace blocks of the base
+        let outside_blocks = |offset| child.error_at(
+            offset,
+            "text outside '@[block]@' is not rendered in a template that extends another",
+        );
+        let mut cursor = extends.end;
+        for block in overrides.iter().filter(|block| block.depth == 0) {
+            if let Some(offset) = engine::first_text(&child.body, cursor..block.span.start) {
+                return Err(outside_blocks(offset));
+            }
+            if !blocks.iter().any(|base| base.name == block.name) {
+                return Err(child.error_at(block.offset, &format!(
+                    "block '{}' is not defined in '{}'", block.name, self.path.display()
+                )));
+            }
+            cursor = block.span.end;
+        }
+        if let Some(offset) = engine::first_text(&child.body, cursor..child.body.len()) {
+            return Err(outside_blocks(offset));
+        }
+
+        let mut composed = child.empty();
+        composed.metadata.description = child.metadata.description.clone().or(self.metadata.description.clone());
+        composed.metadata.author = child.metadata.author.clone().or(self.metadata.author.clone());
+        composed.merge_metadata(&self.metadata);
+
+        let mut cursor = 0;
+        for block in &blocks {
+            // Blocks inside a replaced block are gone with it
+            if block.span.start < cursor {
+                continue;
+            }
+            let Some(replacement) = overrides.iter().find(|o| o.name == block.name) else {
+                continue;
+            };
+            composed.append(self, cursor..block.inner.start);
+
+            let mut from = replacement.inner.start;
+            for (offset, span) in engine::super_tags(&child.body, replacement.inner.clone()) {
+                composed.append(child, from..span.start);
+                // `@[super]@` refers to the innermost block it is written in
+                let owner = overrides.iter()
+                    .filter(|o| o.inner.contains(&offset))
+                    .max_by_key(|o| o.depth)
+                    .unwrap_or(replacement);
+                let parent = blocks.iter().find(|b| b.name == owner.name).ok_or_else(|| child.error_at(
+                    offset,
+                    &format!("'@[super]@' in block '{}', which the base template does not define", owner.name),
+                ))?;
+                composed.append(self, parent.inner.clone());
+                from = span.end;
+            }
+            composed.append(child, from..replacement.inner.end);
+            cursor = block.inner.end;
+        }
+        composed.append(self, cursor..self.body.len());
+        Ok(composed)
+    }
+
+    /// Append a range of another template's body, keeping track of where it came from
+    fn append(&mut self, from: &Template, range: Range<usize>) {
+        for run in from.runs(range) {
+            if run.is_empty() {
+                continue;
+            }
+            let (path, location) = from.locate(run.start);
+            self.chunks.push(Chunk { start: self.body.len(), path: path.to_path_buf(), location });
+            self.body.push_str(&from.body[run]);
+        }
+    }
+
+    /// Append a range of another template's body with each `@[`, and the
+    /// backslashes right before it, wrapped in a raw block so they stay literal
+    fn append_text(&mut self, from: &Template, range: Range<usize>) {
+        let text = &from.body[range.clone()];
+        let mut cursor = range.start;
+        for (i, _) in text.match_indices(OPEN) {
+            let start = range.start + text[..i].trim_end_matches('\\').len();
+            let end = range.start + i + OPEN.len();
+            self.append(from, cursor..start);
+            self.insert(from, start, &format!("{}{}{}", OPEN, Tag::Raw.keyword(), CLOSE));
+            self.append(from, start..end);
+            self.insert(from, end, &format!("{}{}{}", OPEN, Tag::EndRaw.keyword(), CLOSE));
+            cursor = end;
+        }
+        self.append(from, cursor..range.end);
+    }
+
+    /// Append text that is not in another template's body, as if it stood at `origin` there
+    fn insert(&mut self, from: &Template, origin: usize, text: &str) {
+        let (path, location) = from.locate(origin);
+        self.chunks.push(Chunk { start: self.body.len(), path: path.to_path_buf(), location });
+        self.body.push_str(text);
+    }
+
+    /// Resolve `@[if]@` and `@[for]@` blocks against the value map, keeping
+    /// track of where the remaining text came from
+    pub fn expand(&self, values: &HashMap<String, String>) -> Result<Self> {
+        let syntax_error = |e: engine::SyntaxError| self.error_at(e.offset, &e.message);
+        let nodes = engine::parse(&self.body).map_err(syntax_error)?;
+        let mut expansion = Expansion::default();
+        engine::expand(&nodes, &self.body, values, &mut expansion).map_err(syntax_error)?;
+
+        let mut chunks = Vec::new();
+        for (i, &(start, origin)) in expansion.origins.iter().enumerate() {
+            let end = expansion.origins.get(i + 1).map_or(expansion.body.len(), |&(end, _)| end);
+            for run in self.runs(origin..origin + end - start) {
+                let (path, location) = self.locate(run.start);
+                chunks.push(Chunk { start: start + run.start - origin, path: path.to_path_buf(), location });
+            }
+        }
+        Ok(Self {
+            path: self.path.clone(),
+            metadata: self.metadata.clone(),
+            body: expansion.body,
+            chunks,
+            literals: expansion.literals,
+            related: self.related.clone(),
+        })
+    }
+
+    /// Markers within `range` of the body, as the byte range of each and the
+    /// literal text it stands for, in order
+    pub fn literals_in(&self, range: Range<usize>) -> Vec<(Range<usize>, &str)> {
+        let text = &self.body[range.clone()];
+        let mut found: Vec<(Range<usize>, &str)> = self.literals.iter()
+            .flat_map(|(marker, literal)| text.match_indices(marker.as_str())
+                .map(|(i, _)| (range.start + i..range.start + i + marker.len(), literal.as_str())))
+            .collect();
+        found.sort_by_key(|(span, _)| span.start);
+        found
+    }
+
+    /// Put the literal text of raw blocks and escapes back in rendered output
+    pub fn restore(&self, rendered: &str) -> String {
+        self.literals.iter()
+            .fold(rendered.to_string(), |text, (marker, literal)| text.replace(marker.as_str(), literal))
+    }
+
+    /// Placeholders in the body that take a value from the value map, with
+    /// locations relative to the whole file. Loop variables are left out.
+    pub fn placeholders(&self) -> Vec<Placeholder> {
+        let mut occurrences = engine::references(&self.body).placeholders;
+        for occurrence in &mut occurrences {
+            occurrence.location = self.file_location(occurrence.span.start);
+        }
+        placeholder::group(occurrences)
+    }
+
+    /// Comments in the body, in order, located like placeholders
+    pub fn comments(&self) -> Vec<Comment> {
+        engine::comments(&self.body)
+            .into_iter()
+            .map(|(span, text)| Comment { text, location: self.file_location(span.start) })
+            .collect()
+    }
+
+    /// Position of a body offset, naming the file only when it is not this template
+    fn file_location(&self, offset: usize) -> Location {
+        let (path, mut location) = self.locate(offset);
+        if path != self.path {
+            location.path = Some(path.to_path_buf());
+        }
+        location
+    }
+
+    /// Names tested by `@[if]@` and `@[elif]@` conditions, in order of first use
+    pub fn conditions(&self) -> Vec<String> {
+        engine::references(&self.body).conditions
+    }
+
+    /// Lists iterated by `@[for]@`, in order of first use
+    pub fn lists(&self) -> Vec<String> {
+        engine::references(&self.body).lists
+    }
+
+    /// Every name a value can be supplied for: placeholders, condition
+    /// variables, lists and names used by expressions, in any branch
+    pub fn names(&self) -> Vec<String> {
+        let references = engine::references(&self.body);
+        let mut names: Vec<String> = placeholder::group(references.placeholders)
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        let others = references.conditions.into_iter().chain(references.lists).chain(references.optional);
+        for name in others {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Map each placeholder and expression key of an expanded body to the
+    /// value tron sets for it, evaluating expressions against the same values
+    pub fn bind(&self, values: &HashMap<String, String>) -> Result<HashMap<String, String>> {
+        let mut bound = placeholder::bind(&self.body, values);
+        let defaults = placeholder::defaults(&self.body);
+        let lookup = Lookup { values, defaults: &defaults };
+        for occurrence in placeholder::values(&self.body) {
+            if !expr::is_expression(&occurrence.key) || !occurrence.is_substituted(&self.body) {
+                continue;
+            }
+            let start = occurrence.key_start(&self.body);
+            let value = Expr::parse(&occurrence.key)
+                .and_then(|expression| expression.eval(&lookup))
+                .map_err(|e| self.error_at(start + e.offset, &format!("in '@[{}]@': {}", occurrence.key, e.message)))?;
+            bound.insert(occurrence.key, value);
+        }
+        Ok(bound)
+    }
+
+    /// Render an expanded template with bound values, then put back the
+    /// literal text of its raw blocks and escapes
+    pub fn render(&self, bound: &HashMap<String, String>) -> Result<String> {
+        let values = TronValues::new(bound);
+        let mut template = TronTemplate::new(&self.body)?;
+        for (key, value) in &values.values {
+            // Keys the template does not use are skipped
+            match template.set(key, value) {
+                Err(TronError::MissingPlaceholder(_)) => {}
+                result => result?,
+            }
+        }
+        Ok(self.restore(&values.restore(&template.render()?)))
+    }
+
+    /// Validate the value each placeholder will receive, supplied or default,
+    /// against its declared type, reporting every violation at once
+    pub fn validate_values(&self, values: &HashMap<String, String>) -> Result<()> {
+        let mut problems = Vec::new();
+        for placeholder in self.placeholders() {
+            let name = list_name(&placeholder.name).unwrap_or(&placeholder.name);
+            let Some(doc) = self.metadata.placeholders.get(name) else {
+                continue;
+            };
+            let value = placeholder::supplied(values, &placeholder.name)
+                .or(placeholder.default.as_ref());
+            if let Some(Err(reason)) = value.map(|value| doc.check(value)) {
+                problems.push(format!(
+                    "{}: invalid value for '{}': {}",
+                    placeholder.locations[0].describe(&self.path), placeholder.name, reason
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(TronError::Parse(format!("Invalid placeholder values:\n  {}", problems.join("\n  "))))
+        }
+    }
+}
+
+/// Whether two paths name the same file, comparing them as given when either
+/// cannot be resolved
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(content: &str, values: &[(&str, &str)]) -> String {
+        let values: HashMap<String, String> = values.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let template = Template::parse(Path::new("t.tmrs"), content).unwrap().expand(&values).unwrap();
+        template.render(&template.bind(&values).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn expressions_may_render_empty() {
+        let content = "[@[= join(fields, \", \")]@][@[= default(x, \"\")]@][@[= trim(\" \") ~ default(x, \"\")]@]";
+        assert_eq!(rendered(content, &[]), "[][][]");
+        assert_eq!(rendered(content, &[("fields.0", "a"), ("fields.1", "b"), ("x", "y")]), "[a, b][y][y]");
+    }
+
+    #[test]
+    fn empty_defaults_make_placeholders_optional() {
+        assert_eq!(rendered("x@[suffix:]@ @[suffix|upper]@.", &[]), "x .");
+        assert_eq!(rendered("x@[suffix:]@ @[suffix|upper]@.", &[("suffix", "s")]), "xs S.");
+    }
+
+    #[test]
+    fn filters_may_produce_empty_values() {
+        assert_eq!(rendered("[@[name|trim]@][@[name|raw]@]", &[("name", "  ")]), "[][  ]");
+        assert_eq!(rendered("[@[name|trim|upper]@]", &[("name", " a ")]), "[A]");
+    }
+}
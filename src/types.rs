@@ -0,0 +1,81 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Kind of value a placeholder accepts, declared with `type = "..."` in front-matter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueType {
+    /// A Rust identifier such as `user_name` (keywords are rejected)
+    Ident,
+    /// A Rust type path such as `std::collections::HashMap<String, u32>`
+    TypePath,
+    /// An integer literal, optionally negative and suffixed (`-3`, `42u8`)
+    Integer,
+    /// A float literal, optionally negative (`1.5`, `2e10f32`)
+    Float,
+    /// `true` or `false`
+    Bool,
+    /// A Rust string literal including its quotes (`"hi"`, `r#"raw"#`)
+    StringLiteral,
+    /// One of the values listed in `choices`
+    Enum,
+    /// Any value fully matching the regular expression in `pattern`
+    Regex,
+}
+
+impl ValueType {
+    /// Check a value against this type, describing the mismatch if there is one
+    pub fn check(self, value: &str, choices: &[String], pattern: Option<&str>) -> Result<(), String> {
+        let unsigned = value.strip_prefix('-').unwrap_or(value);
+        let valid = match self {
+            ValueType::Ident => syn::parse_str::<syn::Ident>(value).is_ok(),
+            ValueType::TypePath => syn::parse_str::<syn::TypePath>(value).is_ok(),
+            ValueType::Integer => syn::parse_str::<syn::LitInt>(unsigned).is_ok(),
+            ValueType::Float => syn::parse_str::<syn::LitFloat>(unsigned).is_ok(),
+            ValueType::Bool => value == "true" || value == "false",
+            ValueType::StringLiteral => syn::parse_str::<syn::LitStr>(value).is_ok(),
+            ValueType::Enum => choices.iter().any(|choice| choice == value),
+            ValueType::Regex => {
+                let pattern = pattern.unwrap_or_default();
+                let regex = compile_pattern(pattern)?;
+                regex.is_match(value)
+            }
+        };
+
+        if valid {
+            return Ok(());
+        }
+        Err(match self {
+            ValueType::Ident => format!("{:?} is not a Rust identifier", value),
+            ValueType::TypePath => format!("{:?} is not a Rust type path", value),
+            ValueType::Integer => format!("{:?} is not an integer", value),
+            ValueType::Float => format!("{:?} is not a float", value),
+            ValueType::Bool => format!("{:?} is not a bool (expected true or false)", value),
+            ValueType::StringLiteral => format!("{:?} is not a Rust string literal", value),
+            ValueType::Enum => format!("{:?} is not one of {}", value, choices.join(", ")),
+            ValueType::Regex => format!("{:?} does not match /{}/", value, pattern.unwrap_or_default()),
+        })
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ValueType::Ident => "ident",
+            ValueType::TypePath => "type_path",
+            ValueType::Integer => "integer",
+            ValueType::Float => "float",
+            ValueType::Bool => "bool",
+            ValueType::StringLiteral => "string_literal",
+            ValueType::Enum => "enum",
+            ValueType::Regex => "regex",
+        })
+    }
+}
+
+/// Compile a `pattern` so that it must match the whole value
+pub fn compile_pattern(pattern: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{})$", pattern))
+        .map_err(|e| format!("invalid pattern /{}/: {}", pattern, e))
+}
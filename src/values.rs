@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tron::{Result, TronError};
+
+use crate::stdio;
+
+/// How repeated keys in `-v` arguments are handled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicatePolicy {
+    /// Reject the arguments
+    #[default]
+    Error,
+    /// Print a warning and keep the last value
+    Warn,
+    /// Silently keep the last value
+    Allow,
+}
+
+/// A malformed `key=value` command line argument
+#[derive(Debug)]
+pub enum ValueArgError {
+    /// The argument has no `=` separator
+    MissingSeparator { arg: String },
+    /// The argument has nothing before the `=`
+    EmptyKey { arg: String },
+    /// The key cannot be used as a placeholder name
+    InvalidKey { arg: String, key: String },
+    /// The key was given more than once
+    DuplicateKey { key: String },
+    /// The value contains an escape sequence `--unescape` does not understand
+    InvalidEscape { arg: String, reason: String },
+    /// The `@file` or `@-` the value refers to could not be read
+    UnreadableFile { arg: String, reason: String },
+}
+
+impl fmt::Display for ValueArgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingSeparator { arg } => {
+                write!(f, "Invalid argument '{}': expected key=value", arg)
+            }
+            Self::EmptyKey { arg } => write!(f, "Invalid argument '{}': key is empty", arg),
+            Self::InvalidKey { arg, key } => write!(
+                f,
+                "Invalid argument '{}': '{}' is not a valid placeholder name \
+                 (it cannot contain ']' or '=')",
+                arg, key
+            ),
+            Self::DuplicateKey { key } => write!(f, "Key '{}' was given more than once", key),
+            Self::InvalidEscape { arg, reason } => {
+                write!(f, "Invalid argument '{}': {}", arg, reason)
+            }
+            Self::UnreadableFile { arg, reason } => {
+                write!(f, "Cannot read value for '{}': {}", arg, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValueArgError {}
+
+impl From<ValueArgError> for TronError {
+    fn from(err: ValueArgError) -> Self {
+        TronError::Parse(err.to_string())
+    }
+}
+
+/// Check a supplied key against the names tron accepts between the
+/// delimiters, so any placeholder of a template can be given a value
+pub fn is_value_key(key: &str) -> bool {
+    !key.is_empty() && !key.contains([']', '='])
+}
+
+/// Check a name against the rules for names in block tags, such as the
+/// variable and list of `@[for]@`
+pub fn is_placeholder_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Suffix of a `-v` key that appends an item to a list, as in `-v fields[]=id`
+pub const LIST_SUFFIX: &str = "[]";
+
+/// Key under which item `index` of the list `name` is stored in the value map.
+/// List items live alongside plain values; the `.` keeps them apart from
+/// anything a user can supply directly.
+pub fn list_key(name: &str, index: usize) -> String {
+    format!("{}.{}", name, index)
+}
+
+/// Name of the list a key holds an item of, or `None` for plain values
+pub fn list_name(key: &str) -> Option<&str> {
+    let (name, index) = key.split_once('.')?;
+    index.parse::<usize>().ok().map(|_| name)
+}
+
+/// Number of items in the list `name`; zero when it was never supplied
+pub fn list_len(values: &HashMap<String, String>, name: &str) -> usize {
+    (0..).take_while(|&index| values.contains_key(&list_key(name, index))).count()
+}
+
+/// Split a single `key=value` argument, rejecting a missing `=` or empty key
+pub fn split_key_value(arg: &str) -> std::result::Result<(&str, &str), ValueArgError> {
+    let (key, value) = arg.split_once('=').ok_or_else(|| ValueArgError::MissingSeparator {
+        arg: arg.to_string(),
+    })?;
+    if key.is_empty() {
+        return Err(ValueArgError::EmptyKey { arg: arg.to_string() });
+    }
+    Ok((key, value))
+}
+
+/// Process `\n`, `\r`, `\t`, `\0`, `\\`, `\"`, `\'` and `\u{...}` escapes in a value
+pub fn unescape(value: &str) -> std::result::Result<String, String> {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some('0') => result.push('\0'),
+            Some('\\') => result.push('\\'),
+            Some('"') => result.push('"'),
+            Some('\'') => result.push('\''),
+            Some('u') => {
+                if chars.next() != Some('{') {
+                    return Err("expected '{' after \\u".into());
+                }
+                let mut hex = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => hex.push(c),
+                        None => return Err(format!("unterminated unicode escape \\u{{{}", hex)),
+                    }
+                }
+                let code = u32::from_str_radix(&hex, 16)
+                    .map_err(|_| format!("invalid unicode escape \\u{{{}}}", hex))?;
+                let c = char::from_u32(code)
+                    .ok_or_else(|| format!("\\u{{{}}} is not a valid character", hex))?;
+                result.push(c);
+            }
+            Some(other) => return Err(format!("unknown escape sequence \\{}", other)),
+            None => return Err("trailing backslash".into()),
+        }
+    }
+    Ok(result)
+}
+
+/// Read a value referenced as `@path`, or `@-` for stdin, dropping one trailing newline
+fn read_value_file(arg: &str, target: &str) -> std::result::Result<String, ValueArgError> {
+    let unreadable = |reason: String| ValueArgError::UnreadableFile { arg: arg.to_string(), reason };
+
+    if target.is_empty() {
+        return Err(unreadable("missing file name after '@' (use '@@' for a literal '@')".into()));
+    }
+
+    let mut content = stdio::read_input(Path::new(target)).map_err(|e| match e {
+        TronError::Parse(reason) => unreadable(reason),
+        TronError::Io(e) => unreadable(format!("{}: {}", target, e)),
+        e => unreadable(e.to_string()),
+    })?;
+
+    if content.ends_with('\n') {
+        content.pop();
+        if content.ends_with('\r') {
+            content.pop();
+        }
+    }
+    Ok(content)
+}
+
+/// Parse key-value pairs from command line arguments.
+///
+/// A value of `@path` is read from that file and `@-` from stdin; `@@` stands
+/// for a literal leading `@`. Escapes are processed in inline values only.
+/// A key ending in `[]` appends the value to a list instead.
+pub fn parse_key_values(
+    pairs: &[String],
+    duplicates: DuplicatePolicy,
+    unescape_values: bool,
+) -> std::result::Result<HashMap<String, String>, ValueArgError> {
+    let mut values = HashMap::new();
+    for pair in pairs {
+        let (key, value) = split_key_value(pair)?;
+        let (key, is_item) = match key.strip_suffix(LIST_SUFFIX) {
+            Some(name) => (name, true),
+            None => (key, false),
+        };
+        if !is_value_key(key) {
+            return Err(ValueArgError::InvalidKey { arg: pair.clone(), key: key.to_string() });
+        }
+
+        let value = match value.strip_prefix('@') {
+            Some(target) if !target.starts_with('@') => {
+                read_value_file(pair, target)?
+            }
+            literal => {
+                let value = literal.unwrap_or(value);
+                if unescape_values {
+                    unescape(value).map_err(|reason| ValueArgError::InvalidEscape {
+                        arg: pair.clone(),
+                        reason,
+                    })?
+                } else {
+                    value.to_string()
+                }
+            }
+        };
+
+        if is_item {
+            let index = list_len(&values, key);
+            values.insert(list_key(key, index), value);
+        } else if values.insert(key.to_string(), value).is_some() {
+            match duplicates {
+                DuplicatePolicy::Error => {
+                    return Err(ValueArgError::DuplicateKey { key: key.to_string() })
+                }
+                DuplicatePolicy::Warn => {
+                    eprintln!("Warning: key '{}' given more than once, using the last value", key)
+                }
+                DuplicatePolicy::Allow => {}
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Validate a `name=version` dependency argument
+pub fn parse_dependency(arg: &str) -> std::result::Result<&str, ValueArgError> {
+    let (name, _) = split_key_value(arg)?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ValueArgError::EmptyKey { arg: arg.to_string() });
+    }
+    Ok(arg)
+}
+
+/// Where a resolved placeholder value came from
+#[derive(Debug, Clone)]
+pub enum ValueSource {
+    /// A `--values-file`
+    File(PathBuf),
+    /// An environment variable, by full name
+    Env(String),
+    /// A `-v key=value` argument
+    Arg,
+}
+
+impl fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "file {}", path.display()),
+            Self::Env(var) => write!(f, "env {}", var),
+            Self::Arg => f.write_str("-v argument"),
+        }
+    }
+}
+
+/// Load placeholder values from environment variables named `<prefix><key>`.
+/// Variables that share the prefix but cannot be a value, because of their
+/// key or a name or value that is not UTF-8, are skipped with a warning.
+pub fn load_env_values(prefix: &str) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    for (var, value) in std::env::vars_os() {
+        let name = var.to_string_lossy();
+        let Some(key) = name.strip_prefix(prefix) else {
+            continue;
+        };
+        let value = match (var.to_str(), value.into_string()) {
+            (Some(_), Ok(value)) if is_value_key(key) => value,
+            (Some(_), Ok(_)) => {
+                eprintln!("Warning: skipping environment variable '{}': '{}' is not a valid placeholder name", name, key);
+                continue;
+            }
+            _ => {
+                eprintln!("Warning: skipping environment variable '{}': not valid UTF-8", name);
+                continue;
+            }
+        };
+        values.insert(key.to_string(), value);
+    }
+    values
+}
+
+/// Load placeholder values from a TOML, JSON or YAML file, detected by extension
+pub fn load_values_file(path: &Path) -> Result<HashMap<String, String>> {
+    let content = fs::read_to_string(path)?;
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    let document: serde_json::Value = match extension.as_deref() {
+        Some("toml") => toml::from_str(&content)
+            .map_err(|e| parse_error(path, e))?,
+        Some("json") => serde_json::from_str(&content)
+            .map_err(|e| parse_error(path, e))?,
+        Some("yaml") | Some("yml") => serde_yaml_ng::from_str(&content)
+            .map_err(|e| parse_error(path, e))?,
+        _ => return Err(TronError::Parse(format!(
+            "Unsupported values file '{}': expected a .toml, .json, .yaml or .yml extension",
+            path.display()
+        ))),
+    };
+
+    let table = match document {
+        serde_json::Value::Object(table) => table,
+        serde_json::Value::Null => return Ok(HashMap::new()),
+        _ => return Err(TronError::Parse(format!(
+            "Values file '{}' must contain a table of key/value pairs",
+            path.display()
+        ))),
+    };
+
+    let in_file = |e: String| TronError::Parse(format!("{} (in '{}')", e, path.display()));
+    let mut values = HashMap::new();
+    for (key, value) in table {
+        if !is_value_key(&key) {
+            return Err(TronError::Parse(format!(
+                "Key '{}' in '{}' is not a valid placeholder name",
+                key, path.display()
+            )));
+        }
+        if let serde_json::Value::Array(items) = value {
+            for (index, item) in items.into_iter().enumerate() {
+                let item = scalar_to_string(&key, item).map_err(in_file)?;
+                values.insert(list_key(&key, index), item);
+            }
+        } else {
+            values.insert(key.clone(), scalar_to_string(&key, value).map_err(in_file)?);
+        }
+    }
+    Ok(values)
+}
+
+/// Convert a scalar from a values file into the string handed to the template
+fn scalar_to_string(key: &str, value: serde_json::Value) -> std::result::Result<String, String> {
+    match value {
+        serde_json::Value::String(s) => Ok(s),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        serde_json::Value::Bool(b) => Ok(b.to_string()),
+        serde_json::Value::Null => Err(format!("Value for '{}' is null", key)),
+        _ => Err(format!(
+            "Value for '{}' must be a string, number, boolean or a list of those",
+            key
+        )),
+    }
+}
+
+fn parse_error(path: &Path, err: impl std::fmt::Display) -> TronError {
+    TronError::Parse(format!("Failed to parse values file '{}': {}", path.display(), err))
+}